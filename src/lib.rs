@@ -0,0 +1,1114 @@
+//! The diagram model behind term-draw: a sparse grid of glyphs, the line glyph sets, the shapes
+//! the editor draws and the exports, usable from other programs without the terminal UI.
+
+use std::{
+    collections::{hash_map, HashMap},
+    ops::{Deref, DerefMut},
+};
+
+use anyhow::Result;
+pub use ratatui::style::Color;
+use unicode_width::UnicodeWidthChar;
+
+#[cfg(feature = "png")]
+mod png;
+
+// a committed glyph and its own color, so styling travels with it
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Cell {
+    pub char: char,
+    pub fg: Option<Color>,
+}
+
+// glyphs a shape puts down, keyed by (x, y)
+pub type Glyphs = HashMap<(i32, i32), char>;
+
+// blank cells added around cropped output as (left, top, right, bottom)
+pub type Margin = (i32, i32, i32, i32);
+
+// sides of a cell a line leaves through
+pub const UP: u8 = 1;
+pub const DOWN: u8 = 2;
+pub const LEFT: u8 = 4;
+pub const RIGHT: u8 = 8;
+
+// a no-break space, a cell that counts as drawn, so trimming and cropping keep it, but shows blank
+pub const HARD_SPACE: char = '\u{a0}';
+
+pub fn side(direction: (i32, i32)) -> u8 {
+    match direction {
+        (0, -1) => UP,
+        (0, 1) => DOWN,
+        (-1, 0) => LEFT,
+        _ => RIGHT,
+    }
+}
+
+pub fn step((x, y): (i32, i32), side: u8) -> (i32, i32) {
+    match side {
+        UP => (x, y - 1),
+        DOWN => (x, y + 1),
+        LEFT => (x - 1, y),
+        _ => (x + 1, y),
+    }
+}
+
+pub fn opposite(side: u8) -> u8 {
+    match side {
+        UP => DOWN,
+        DOWN => UP,
+        LEFT => RIGHT,
+        _ => LEFT,
+    }
+}
+
+// line glyphs joining the given sides of a cell, indexed by the side bits
+pub const GLYPH_SETS: [(&str, [char; 16]); 5] = [
+    ("light", [' ', '│', '│', '│', '─', '╯', '╮', '┤', '─', '╰', '╭', '├', '─', '┴', '┬', '┼']),
+    ("square", [' ', '│', '│', '│', '─', '┘', '┐', '┤', '─', '└', '┌', '├', '─', '┴', '┬', '┼']),
+    ("heavy", [' ', '┃', '┃', '┃', '━', '┛', '┓', '┫', '━', '┗', '┏', '┣', '━', '┻', '┳', '╋']),
+    ("double", [' ', '║', '║', '║', '═', '╝', '╗', '╣', '═', '╚', '╔', '╠', '═', '╩', '╦', '╬']),
+    ("ascii", [' ', '|', '|', '|', '-', '+', '+', '+', '-', '+', '+', '+', '-', '+', '+', '+']),
+];
+
+// arrow heads pointing up, down, left and right, for each weight
+pub const HEAD_SETS: [(&str, [char; 4]); 3] = [
+    ("light", ['▴', '▾', '◂', '▸']),
+    ("heavy", ['▲', '▼', '◀', '▶']),
+    ("ascii", ['^', 'v', '<', '>']),
+];
+
+// the side an arrow head points to, only the light and heavy heads count since the ascii ones
+// are as likely to be letters
+pub fn head_side(char: char) -> Option<u8> {
+    HEAD_SETS[..2]
+        .iter()
+        .find_map(|(_, heads)| heads.iter().position(|head| *head == char))
+        .map(|i| [UP, DOWN, LEFT, RIGHT][i])
+}
+
+// sides a line glyph from any of the sets joins, ascii '+' counts as joining all four
+pub fn glyph_sides(char: char) -> Option<u8> {
+    GLYPH_SETS
+        .iter()
+        .find_map(|(_, lines)| lines.iter().rposition(|glyph| *glyph == char))
+        .filter(|sides| *sides != 0)
+        .map(|sides| sides as u8)
+}
+
+// the glyph joining `sides` in whichever set `char` comes from
+pub fn glyph_like(char: char, sides: u8) -> char {
+    GLYPH_SETS
+        .iter()
+        .find(|(_, lines)| lines.contains(&char))
+        .map_or(char, |(_, lines)| lines[sides as usize])
+}
+
+// the line style used by boxes and arrows, one of GLYPH_SETS picked by name, with the arrow
+// heads of one of HEAD_SETS
+#[derive(Clone, Debug)]
+pub struct BoxStyle {
+    lines: [char; 16],
+    heads: [char; 4],
+    // an arrow that never leaves its first cell, None draws nothing
+    point: Option<char>,
+}
+
+impl Default for BoxStyle {
+    fn default() -> BoxStyle {
+        BoxStyle {
+            lines: GLYPH_SETS[0].1,
+            heads: HEAD_SETS[1].1,
+            point: Some('◆'),
+        }
+    }
+}
+
+impl BoxStyle {
+    pub fn named(name: &str) -> Result<BoxStyle> {
+        match GLYPH_SETS.iter().find(|set| set.0 == name) {
+            Some((_, lines)) => Ok(BoxStyle { lines: *lines, ..BoxStyle::default() }),
+            None => anyhow::bail!(
+                "unknown glyph set {name}, expected one of {}",
+                GLYPH_SETS.map(|set| set.0).join(", ")
+            ),
+        }
+    }
+
+    // the lines and heads of one weight together, `light`, `heavy` or `ascii`
+    pub fn weighted(weight: &str) -> Result<BoxStyle> {
+        match HEAD_SETS.iter().find(|set| set.0 == weight) {
+            Some((name, heads)) => Ok(BoxStyle { heads: *heads, ..BoxStyle::named(name)? }),
+            None => anyhow::bail!("expected light, heavy or ascii, got {weight}"),
+        }
+    }
+
+    pub fn with_point(self, point: Option<char>) -> BoxStyle {
+        BoxStyle { point, ..self }
+    }
+
+    pub fn line(&self, sides: u8) -> char {
+        self.lines[(sides & 0xf) as usize]
+    }
+
+    pub fn head(&self, direction: (i32, i32)) -> char {
+        match direction {
+            (0, -1) => self.heads[0],
+            (0, 1) => self.heads[1],
+            (-1, 0) => self.heads[2],
+            _ => self.heads[3],
+        }
+    }
+}
+
+// the heavy head, whatever the style
+pub fn arrow_head(direction: (i32, i32)) -> char {
+    BoxStyle::default().head(direction)
+}
+
+// cells from a to b, horizontal leg first, both ends included
+pub fn elbow(a: (i32, i32), b: (i32, i32)) -> Vec<(i32, i32)> {
+    let mut cells = vec![a];
+    let mut pos = a;
+    while pos.0 != b.0 {
+        pos.0 = if pos.0 < b.0 { pos.0 + 1 } else { pos.0 - 1 };
+        cells.push(pos);
+    }
+    while pos.1 != b.1 {
+        pos.1 = if pos.1 < b.1 { pos.1 + 1 } else { pos.1 - 1 };
+        cells.push(pos);
+    }
+    cells
+}
+
+// the border of (left, top, right, bottom)
+pub fn box_glyphs(area: (i32, i32, i32, i32), style: &BoxStyle) -> Glyphs {
+    box_sides_glyphs(area, UP | DOWN | LEFT | RIGHT, style)
+}
+
+// the border with only some of its sides, UP for the top one, DOWN for the bottom and so on,
+// the corners turn where two drawn sides meet and carry a lone side through otherwise
+pub fn box_sides_glyphs(
+    (left, top, right, bottom): (i32, i32, i32, i32),
+    sides: u8,
+    style: &BoxStyle,
+) -> Glyphs {
+    let drawn = |&(side, _): &(u8, i32)| sides & side != 0;
+    let mut links: HashMap<(i32, i32), u8> = HashMap::new();
+    for (_, y) in [(UP, top), (DOWN, bottom)].into_iter().filter(drawn) {
+        for x in left..=right {
+            let link = links.entry((x, y)).or_default();
+            *link |= if x > left { LEFT } else { 0 } | if x < right { RIGHT } else { 0 };
+        }
+    }
+    for (_, x) in [(LEFT, left), (RIGHT, right)].into_iter().filter(drawn) {
+        for y in top..=bottom {
+            let link = links.entry((x, y)).or_default();
+            *link |= if y > top { UP } else { 0 } | if y < bottom { DOWN } else { 0 };
+        }
+    }
+    links.into_iter().map(|(pos, link)| (pos, style.line(link))).collect()
+}
+
+// an arrow through the points in order, each leg an elbow, with its head on the last point
+pub fn arrow_glyphs(points: &[(i32, i32)], style: &BoxStyle) -> Glyphs {
+    let Some(&start) = points.first() else {
+        return Glyphs::new();
+    };
+    // walking back over the last cell retracts the arrow instead of doubling it
+    let mut path: Vec<(i32, i32)> = vec![start];
+    for leg in points.windows(2) {
+        for pos in elbow(leg[0], leg[1]).into_iter().skip(1) {
+            if path.len() >= 2 && path[path.len() - 2] == pos {
+                path.pop();
+            } else {
+                path.push(pos);
+            }
+        }
+    }
+
+    let mut glyphs = Glyphs::new();
+    if path.len() == 1 {
+        glyphs.extend(style.point.map(|point| (path[0], point)));
+        return glyphs;
+    }
+    // every glyph comes from which neighbours the path links it to, so crossings join up
+    let direction = |a: (i32, i32), b: (i32, i32)| (b.0 - a.0, b.1 - a.1);
+    let mut sides: HashMap<(i32, i32), u8> = HashMap::new();
+    for step in path.windows(2) {
+        *sides.entry(step[0]).or_default() |= side(direction(step[0], step[1]));
+        *sides.entry(step[1]).or_default() |= side(direction(step[1], step[0]));
+    }
+    for (pos, sides) in sides {
+        glyphs.insert(pos, style.line(sides));
+    }
+    // the head goes on after the whole body, so it is the tip even where the path crosses or
+    // comes back over its last cell
+    let last = path.len() - 1;
+    glyphs.insert(path[last], style.head(direction(path[last - 1], path[last])));
+    glyphs
+}
+
+// one quadrant of the ellipse with radii a and b from the midpoint algorithm, running from the
+// top (0, b) round to the side (a, 0), y grows upwards
+fn ellipse_quadrant(a: i32, b: i32) -> Vec<(i32, i32)> {
+    if b == 0 {
+        return (0..=a).map(|x| (x, 0)).collect();
+    }
+    let (a2, b2) = ((a * a) as f64, (b * b) as f64);
+    let (mut x, mut y) = (0, b);
+    let mut points = Vec::new();
+    let mut d1 = b2 - a2 * b as f64 + a2 / 4.0;
+    while b2 * x as f64 * 2.0 < a2 * y as f64 * 2.0 {
+        points.push((x, y));
+        x += 1;
+        if d1 < 0.0 {
+            d1 += b2 * (2 * x + 1) as f64;
+        } else {
+            y -= 1;
+            d1 += b2 * (2 * x + 1) as f64 - a2 * (2 * y) as f64;
+        }
+    }
+    let mut d2 = b2 * (x as f64 + 0.5).powi(2) + a2 * ((y - 1) as f64).powi(2) - a2 * b2;
+    while y >= 0 {
+        points.push((x, y));
+        y -= 1;
+        if d2 > 0.0 {
+            d2 += a2 * (1 - 2 * y) as f64;
+        } else {
+            x += 1;
+            d2 += b2 * (2 * x) as f64 + a2 * (1 - 2 * y) as f64;
+        }
+    }
+    points
+}
+
+// the ellipse filling (left, top, right, bottom), drawn as one closed line so the glyphs join
+// like arrows do
+pub fn ellipse_glyphs(area: (i32, i32, i32, i32), style: &BoxStyle) -> Glyphs {
+    let (left, top, right, bottom) = area;
+    let mut glyphs = Glyphs::new();
+    if left == right && top == bottom {
+        glyphs.insert((left, top), 'o');
+        return glyphs;
+    }
+    let (a, b) = ((right - left) / 2, (bottom - top) / 2);
+    // odd sizes have two centre columns (or rows), the halves are drawn from either one
+    let (center_left, center_right) = (left + a, right - a);
+    let (center_top, center_bottom) = (top + b, bottom - b);
+    let quadrant = ellipse_quadrant(a, b);
+    let mut outline: Vec<(i32, i32)> = Vec::new();
+    outline.extend(quadrant.iter().map(|&(x, y)| (center_right + x, center_top - y)));
+    outline.extend(quadrant.iter().rev().map(|&(x, y)| (center_right + x, center_bottom + y)));
+    outline.extend(quadrant.iter().map(|&(x, y)| (center_left - x, center_bottom + y)));
+    outline.extend(quadrant.iter().rev().map(|&(x, y)| (center_left - x, center_top - y)));
+
+    // diagonal steps get the cell outside the curve between them so every step is a line
+    let center = ((left + right) as f64 / 2.0, (top + bottom) as f64 / 2.0);
+    let radii = ((a as f64).max(0.5), (b as f64).max(0.5));
+    let distance = |(x, y): (i32, i32)| {
+        ((x as f64 - center.0) / radii.0).powi(2) + ((y as f64 - center.1) / radii.1).powi(2)
+    };
+    let mut path: Vec<(i32, i32)> = Vec::new();
+    for i in 0..outline.len() {
+        let (from, to) = (outline[i], outline[(i + 1) % outline.len()]);
+        path.push(from);
+        if from.0 != to.0 && from.1 != to.1 {
+            let corners = [(to.0, from.1), (from.0, to.1)];
+            let outside = if distance(corners[0]) >= distance(corners[1]) {
+                corners[0]
+            } else {
+                corners[1]
+            };
+            path.push(outside);
+        }
+    }
+
+    let mut sides: HashMap<(i32, i32), u8> = HashMap::new();
+    for i in 0..path.len() {
+        let (from, to) = (path[i], path[(i + 1) % path.len()]);
+        if from != to {
+            *sides.entry(from).or_default() |= side((to.0 - from.0, to.1 - from.1));
+            *sides.entry(to).or_default() |= side((from.0 - to.0, from.1 - to.1));
+        }
+    }
+    for (pos, sides) in sides {
+        glyphs.insert(pos, style.line(sides));
+    }
+    glyphs
+}
+
+// a grid of rows x cols boxes of width x height inside, sharing their borders, top-left corner
+// at (left, top), with junctions where the lines cross, refused when it would reach column or
+// row `max`
+pub fn table_glyphs(
+    (left, top): (i32, i32),
+    (rows, cols, width, height): (i32, i32, i32, i32),
+    style: &BoxStyle,
+    max: i32,
+) -> Result<Glyphs> {
+    let edge = |start: i32, count: i32, size: i32| {
+        count.checked_mul(size.checked_add(1)?)?.checked_add(start).filter(|edge| *edge < max)
+    };
+    let (Some(right), Some(bottom)) = (edge(left, cols, width), edge(top, rows, height)) else {
+        anyhow::bail!("a {rows}x{cols} table of {width}x{height} cells doesn't fit in {max} cells");
+    };
+    let mut glyphs = Glyphs::new();
+    for y in top..=bottom {
+        for x in left..=right {
+            let mut sides = 0;
+            if (y - top) % (height + 1) == 0 {
+                sides |= if x > left { LEFT } else { 0 } | if x < right { RIGHT } else { 0 };
+            }
+            if (x - left) % (width + 1) == 0 {
+                sides |= if y > top { UP } else { 0 } | if y < bottom { DOWN } else { 0 };
+            }
+            if sides != 0 {
+                glyphs.insert((x, y), style.line(sides));
+            }
+        }
+    }
+    Ok(glyphs)
+}
+
+// the drawing itself: committed cells keyed by (x, y), read and written like the map it wraps
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct Canvas {
+    cells: HashMap<(i32, i32), Cell>,
+    // every glyph takes one cell, for terminals that draw wide glyphs narrow
+    pub single_width: bool,
+}
+
+impl Deref for Canvas {
+    type Target = HashMap<(i32, i32), Cell>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.cells
+    }
+}
+
+impl DerefMut for Canvas {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.cells
+    }
+}
+
+impl<'a> IntoIterator for &'a Canvas {
+    type Item = (&'a (i32, i32), &'a Cell);
+    type IntoIter = hash_map::Iter<'a, (i32, i32), Cell>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.cells.iter()
+    }
+}
+
+impl Canvas {
+    pub fn new() -> Canvas {
+        Canvas::default()
+    }
+
+    // cells taken up by a glyph on screen
+    pub fn advance(&self, char: char) -> i32 {
+        if self.single_width {
+            1
+        } else {
+            char.width().unwrap_or(1).max(1) as i32
+        }
+    }
+
+    // rectangle around the content as (left, top, right, bottom)
+    pub fn bounds(&self) -> Option<(i32, i32, i32, i32)> {
+        if self.cells.is_empty() {
+            return None;
+        }
+        let left = self.cells.keys().map(|pos| pos.0).min().unwrap();
+        let right = self.cells.keys().map(|pos| pos.0).max().unwrap();
+        let top = self.cells.keys().map(|pos| pos.1).min().unwrap();
+        let bottom = self.cells.keys().map(|pos| pos.1).max().unwrap();
+        Some((left, top, right, bottom))
+    }
+
+    // columns and rows from the origin to the far edges of the content, (0, 0) when empty
+    pub fn size(&self) -> (i32, i32) {
+        self.bounds().map_or((0, 0), |(_, _, right, bottom)| (right + 1, bottom + 1))
+    }
+
+    // every glyph other than a space as ((x, y), char), row by row from the top-left
+    pub fn chars(&self) -> impl Iterator<Item = ((i32, i32), char)> {
+        let mut chars: Vec<((i32, i32), char)> = self
+            .cells
+            .iter()
+            .filter(|(_, cell)| cell.char != ' ')
+            .map(|(pos, cell)| (*pos, cell.char))
+            .collect();
+        chars.sort_by_key(|&((x, y), _)| (y, x));
+        chars.into_iter()
+    }
+
+    // each row from the top of the canvas to the bottom of the content as it would be saved,
+    // empty cells as spaces and none trailing
+    pub fn rows(&self) -> impl Iterator<Item = String> + '_ {
+        let (width, height) = self.size();
+        let overlay = Glyphs::new();
+        (0..height).map(move |y| self.row_until(y, width, &overlay))
+    }
+
+    // row `y` from the first column to its last glyph, `overlay` drawn over the cells the way
+    // the editor shows a shape being dragged, empty cells as spaces and none trailing
+    pub fn row(&self, y: i32, overlay: &Glyphs) -> String {
+        let cells = self.cells.iter().map(|(pos, cell)| (*pos, cell.char));
+        let glyphs = overlay.iter().map(|(pos, char)| (*pos, *char));
+        let end = cells
+            .chain(glyphs)
+            .filter(|((_, row), _)| *row == y)
+            .map(|((x, _), char)| x + self.advance(char))
+            .max();
+        self.row_until(y, end.unwrap_or(0), overlay)
+    }
+
+    // row `y` up to column `end`, right-trimmed of plain spaces only so hard spaces stay
+    fn row_until(&self, y: i32, end: i32, overlay: &Glyphs) -> String {
+        let mut row = String::new();
+        let mut x = 0;
+        while x < end {
+            let char = match overlay.get(&(x, y)) {
+                Some(char) => *char,
+                None => self.cells.get(&(x, y)).map_or(' ', |cell| cell.char),
+            };
+            row.push(char);
+            // a wide glyph covers the cell after it
+            x += self.advance(char);
+        }
+        row.truncate(row.trim_end_matches(' ').len());
+        row
+    }
+
+    // commits a shape's glyphs over whatever is there, row by row from the top-left whatever
+    // order the map holds them in, so the result never depends on it
+    pub fn draw(&mut self, glyphs: &Glyphs, fg: Option<Color>) {
+        let mut glyphs: Vec<_> = glyphs.iter().collect();
+        glyphs.sort_by_key(|((x, y), _)| (*y, *x));
+        for (pos, char) in glyphs {
+            self.cells.insert(*pos, Cell { char: *char, fg });
+        }
+    }
+
+    pub fn draw_box(&mut self, area: (i32, i32, i32, i32), style: &BoxStyle) {
+        self.draw(&box_glyphs(area, style), None);
+    }
+
+    pub fn draw_arrow(&mut self, points: &[(i32, i32)], style: &BoxStyle) {
+        self.draw(&arrow_glyphs(points, style), None);
+    }
+
+    pub fn draw_ellipse(&mut self, area: (i32, i32, i32, i32), style: &BoxStyle) {
+        self.draw(&ellipse_glyphs(area, style), None);
+    }
+
+    // places each line of text one row further down, starting at the column of `pos`, spaces
+    // leave what is under them
+    pub fn write_text(&mut self, (start_x, start_y): (i32, i32), text: &str, fg: Option<Color>) {
+        for (y, line) in text.lines().enumerate() {
+            let mut x = start_x;
+            // control characters such as \r or \t have no glyph and take no cell
+            for char in line.chars().filter(|char| !char.is_control()) {
+                if char != ' ' {
+                    self.cells.insert((x, start_y + y as i32), Cell { char, fg });
+                }
+                x = x.saturating_add(self.advance(char));
+            }
+        }
+    }
+
+    // the rows of size starting at origin as text, empty cells as fill or a space
+    pub fn text(&self, origin: (i32, i32), size: (i32, i32), fill: Option<char>) -> String {
+        let mut chars = String::new();
+        for i in origin.1..origin.1 + size.1 {
+            let mut j = origin.0;
+            while j < origin.0 + size.0 {
+                let char = match self.cells.get(&(j, i)) {
+                    Some(cell) => cell.char,
+                    None => fill.unwrap_or(' '),
+                };
+                chars.push(char);
+                // a wide glyph covers the cell after it
+                j += self.advance(char);
+            }
+            chars.push('\n');
+        }
+        chars
+    }
+
+    // the content cropped to its bounding box
+    pub fn crop(&self) -> String {
+        self.crop_margin((0, 0, 0, 0))
+    }
+
+    // the bounding box with `margin` blank cells around it as (left, top, right, bottom), rows
+    // keep their trailing spaces only when there is a right margin to keep
+    pub fn crop_margin(&self, margin: Margin) -> String {
+        let Some((left, top, right, bottom)) = self.bounds() else {
+            return String::new();
+        };
+        let origin = (left - margin.0, top - margin.1);
+        let size = (right + margin.2 - origin.0 + 1, bottom + margin.3 - origin.1 + 1);
+        let chars = self.text(origin, size, None);
+        if margin.2 > 0 {
+            chars
+        } else {
+            trim_trailing_spaces(&chars)
+        }
+    }
+
+    // boxes as (left, top, right, bottom), found by tracing the border from each top-left
+    // corner and sorted top to bottom, left to right
+    pub fn boxes(&self) -> Vec<(i32, i32, i32, i32)> {
+        let sides =
+            |x: i32, y: i32| self.cells.get(&(x, y)).and_then(|cell| glyph_sides(cell.char));
+        let joins = |x: i32, y: i32, wanted: u8| sides(x, y).unwrap_or(0) & wanted == wanted;
+        // text in the top border is a title, including the cell a wide glyph covers
+        let title = |x: i32, y: i32| {
+            let text =
+                |x: i32| self.cells.get(&(x, y)).filter(|cell| glyph_sides(cell.char).is_none());
+            text(x).is_some()
+                || !self.cells.contains_key(&(x, y))
+                    && text(x - 1).is_some_and(|cell| self.advance(cell.char) == 2)
+        };
+
+        let mut boxes = Vec::new();
+        for &(left, top) in self.cells.keys() {
+            if !joins(left, top, RIGHT | DOWN) {
+                continue;
+            }
+            // the first corner along each edge closes the box, so tables split into cells
+            let mut right = left + 1;
+            while (joins(right, top, LEFT | RIGHT) || title(right, top))
+                && !joins(right, top, LEFT | DOWN)
+            {
+                right += 1;
+            }
+            let mut bottom = top + 1;
+            while joins(left, bottom, UP | DOWN) && !joins(left, bottom, UP | RIGHT) {
+                bottom += 1;
+            }
+            if !joins(right, top, LEFT | DOWN) || !joins(left, bottom, UP | RIGHT) {
+                continue;
+            }
+            let closed = joins(right, bottom, LEFT | UP)
+                && (top + 1..bottom).all(|y| joins(right, y, UP | DOWN))
+                && (left + 1..right).all(|x| joins(x, bottom, LEFT | RIGHT));
+            if closed {
+                boxes.push((left, top, right, bottom));
+            }
+        }
+        boxes.sort_by_key(|(left, top, _, _)| (*top, *left));
+        boxes
+    }
+
+    // the box title, or else the text inside it with one line per non-empty row
+    pub fn box_label(&self, (left, top, right, bottom): (i32, i32, i32, i32)) -> Vec<String> {
+        // lines don't belong in a label
+        let row = |y: i32| -> String {
+            (left + 1..right)
+                .map(|x| self.cells.get(&(x, y)).map_or(' ', |cell| cell.char))
+                .map(|char| if glyph_sides(char).is_some() { ' ' } else { char })
+                .collect()
+        };
+        let titled = !row(top).trim().is_empty();
+        let rows = if titled { top..top + 1 } else { top + 1..bottom };
+        rows.map(|y| row(y).trim().to_string()).filter(|row| !row.is_empty()).collect()
+    }
+
+    // follows each arrow back from its head along the line glyphs, only arrows that start and
+    // end on one of `boxes` count, as (from, to) indices into it
+    pub fn edges(&self, boxes: &[(i32, i32, i32, i32)]) -> Vec<(usize, usize)> {
+        let box_at = |(x, y): (i32, i32), margin: i32| {
+            boxes.iter().position(|&(left, top, right, bottom)| {
+                x >= left - margin
+                    && x <= right + margin
+                    && y >= top - margin
+                    && y <= bottom + margin
+            })
+        };
+        let mut edges = Vec::new();
+        for (&(x, y), cell) in &self.cells {
+            let Some(forward) = head_side(cell.char) else {
+                continue;
+            };
+            let head = (x, y);
+            let Some(to) = box_at(step(head, forward), 0).or_else(|| box_at(head, 1)) else {
+                continue;
+            };
+            // walk backwards, leaving each cell by a side other than the one we came in through
+            let mut pos = head;
+            let mut came_from = forward;
+            let mut from = None;
+            for _ in 0..self.cells.len() {
+                let next = step(pos, opposite(came_from));
+                if let Some(found) = box_at(next, 0) {
+                    from = Some(found);
+                    break;
+                }
+                let sides =
+                    self.cells.get(&next).and_then(|cell| glyph_sides(cell.char)).unwrap_or(0);
+                if sides & came_from == 0 {
+                    from = box_at(pos, 1);
+                    break;
+                }
+                let Some(out) = [UP, DOWN, LEFT, RIGHT]
+                    .into_iter()
+                    .find(|side| *side != came_from && sides & side != 0)
+                else {
+                    from = box_at(next, 1);
+                    break;
+                };
+                pos = next;
+                came_from = opposite(out);
+            }
+            if let Some(from) = from.filter(|from| *from != to) {
+                edges.push((from, to));
+            }
+        }
+        edges.sort();
+        edges
+    }
+
+    // rewrites `+`, `-` and `|` that are part of ascii line art in `style`, a `+` joins
+    // whichever neighbours are lines pointing back at it, so `-` and `|` in plain text (a lone
+    // dash, `a|b`) are left alone, and `>` `<` `v` `^` at the end of a line become heads
+    pub fn upgrade_ascii(&mut self, style: &BoxStyle) -> usize {
+        let ascii_sides = |pos: (i32, i32)| match self.cells.get(&pos).map(|cell| cell.char) {
+            Some('-') => LEFT | RIGHT,
+            Some('|') => UP | DOWN,
+            Some('+') => UP | DOWN | LEFT | RIGHT,
+            _ => 0,
+        };
+        let joins = |pos: (i32, i32), side: u8| ascii_sides(step(pos, side)) & opposite(side) != 0;
+        let mut upgrades = Vec::new();
+        let mut heads = Vec::new();
+        for (&pos, cell) in &self.cells {
+            // an arrow head only counts at the end of a line
+            let head = match cell.char {
+                '>' => Some(((1, 0), LEFT)),
+                '<' => Some(((-1, 0), RIGHT)),
+                'v' => Some(((0, 1), UP)),
+                '^' => Some(((0, -1), DOWN)),
+                _ => None,
+            };
+            if let Some((direction, _)) = head.filter(|&(_, tail)| joins(pos, tail)) {
+                heads.push((pos, style.head(direction)));
+            }
+            let possible = ascii_sides(pos);
+            if possible == 0 {
+                continue;
+            }
+            let sides = [UP, DOWN, LEFT, RIGHT]
+                .into_iter()
+                .filter(|&side| possible & side != 0 && joins(pos, side))
+                .fold(0, |sides, side| sides | side);
+            // a corner needs two lines, a straight edge one neighbour along it
+            let keep = match cell.char {
+                '+' => sides.count_ones() >= 2,
+                _ => sides != 0,
+            };
+            if keep {
+                // an edge keeps its full length even where only one end meets a line
+                upgrades.push((pos, if cell.char == '+' { sides } else { possible }));
+            }
+        }
+        let lines = upgrades.iter().map(|&(pos, sides)| (pos, style.line(sides)));
+        let changes: Vec<_> = lines.chain(heads).collect();
+        for &(pos, char) in &changes {
+            if let Some(cell) = self.cells.get_mut(&pos) {
+                cell.char = char;
+            }
+        }
+        changes.len()
+    }
+
+    // redraws every line glyph (ascii ones aside) from its neighbours, joining a side where
+    // either glyph points at the other or an arrow head is entered from it, so stale corners,
+    // tees and crosses left by editing come out right, counts the glyphs that changed
+    pub fn normalize_lines(&mut self) -> usize {
+        let line = |pos: (i32, i32)| {
+            let char = self.cells.get(&pos)?.char;
+            glyph_sides(char).filter(|_| !char.is_ascii()).map(|sides| (char, sides))
+        };
+        // a glyph the light and square sets share takes its set from a neighbour when it can
+        let unique = |char: char| {
+            GLYPH_SETS.iter().filter(|(_, lines)| lines.contains(&char)).count() == 1
+        };
+        let mut changes = Vec::new();
+        for &pos in self.cells.keys() {
+            let Some((char, own)) = line(pos) else {
+                continue;
+            };
+            let mut sides = 0;
+            let mut like = char;
+            for side in [UP, DOWN, LEFT, RIGHT] {
+                let next = step(pos, side);
+                let joined = match line(next) {
+                    Some((other, theirs)) if own & side != 0 || theirs & opposite(side) != 0 => {
+                        if !unique(like) && unique(other) {
+                            like = other;
+                        }
+                        true
+                    }
+                    Some(_) => false,
+                    None => {
+                        self.cells.get(&next).and_then(|cell| head_side(cell.char)) == Some(side)
+                    }
+                };
+                if joined {
+                    sides |= side;
+                }
+            }
+            let glyph = glyph_like(like, sides);
+            if sides != 0 && glyph != char {
+                changes.push((pos, glyph));
+            }
+        }
+        for &(pos, char) in &changes {
+            if let Some(cell) = self.cells.get_mut(&pos) {
+                cell.char = char;
+            }
+        }
+        changes.len()
+    }
+
+    // a copy with every line glyph and arrow head swapped for ascii
+    pub fn to_ascii(&self) -> Canvas {
+        let ascii = BoxStyle::named("ascii").unwrap_or_default();
+        let mut plain = self.clone();
+        for cell in plain.cells.values_mut() {
+            cell.char = match head_side(cell.char) {
+                Some(UP) => '^',
+                Some(DOWN) => 'v',
+                Some(LEFT) => '<',
+                Some(RIGHT) => '>',
+                _ => glyph_sides(cell.char).map_or(cell.char, |sides| ascii.line(sides)),
+            };
+        }
+        plain
+    }
+}
+
+// right-trim every row so lines end at their last glyph, interior spaces are kept as they are
+pub fn trim_trailing_spaces(chars: &str) -> String {
+    let mut trimmed = String::with_capacity(chars.len());
+    for line in chars.lines() {
+        let end = line.trim_end_matches(' ').len();
+        trimmed.push_str(&line[..end]);
+        trimmed.push('\n');
+    }
+    trimmed
+}
+
+// the cells inside (left, top, right, bottom) as text with `overlay` drawn over them, the way
+// the editor shows a shape still being dragged, empty cells as fill or a space
+pub fn render(
+    canvas: &Canvas,
+    overlay: &Glyphs,
+    (left, top, right, bottom): (i32, i32, i32, i32),
+    fill: Option<char>,
+) -> String {
+    let mut chars = String::new();
+    for y in top..=bottom {
+        let mut x = left;
+        while x <= right {
+            let char = match overlay.get(&(x, y)) {
+                Some(char) => *char,
+                None => canvas.get(&(x, y)).map_or(fill.unwrap_or(' '), |cell| cell.char),
+            };
+            chars.push(char);
+            x += canvas.advance(char);
+        }
+        chars.push('\n');
+    }
+    chars
+}
+
+// the content as plain text from the top-left corner of the canvas, rows right-trimmed
+pub fn export_text(canvas: &Canvas) -> String {
+    canvas.rows().map(|row| row + "\n").collect()
+}
+
+// export_text with every line glyph and arrow head in ascii
+pub fn export_ascii(canvas: &Canvas) -> String {
+    export_text(&canvas.to_ascii())
+}
+
+// the cropped content as a source comment, every line padded to the same width, marker `*`
+// makes a /* */ block and anything else starts each line
+pub fn export_comment(canvas: &Canvas, marker: &str, margin: Margin) -> String {
+    let (open, prefix, close) = match marker {
+        "*" => (Some("/*"), " * ".to_string(), Some(" */")),
+        marker => (None, format!("{marker} "), None),
+    };
+    let crop = canvas.crop_margin(margin);
+    let width = |line: &str| line.chars().map(|char| canvas.advance(char)).sum::<i32>();
+    let widest = crop.lines().map(width).max().unwrap_or(0);
+    let mut block = String::new();
+    if let Some(open) = open {
+        block.push_str(open);
+        block.push('\n');
+    }
+    for line in crop.lines() {
+        let pad = " ".repeat((widest - width(line)) as usize);
+        block.push_str(&format!("{prefix}{line}{pad}\n"));
+    }
+    if let Some(close) = close {
+        block.push_str(close);
+        block.push('\n');
+    }
+    block
+}
+
+// a graphviz digraph with a node per box, labelled with its text, and an edge per arrow
+// joining two boxes
+pub fn export_dot(canvas: &Canvas) -> Result<String> {
+    let boxes = canvas.boxes();
+    if boxes.is_empty() {
+        anyhow::bail!("no boxes found");
+    }
+    let mut dot = String::from("digraph {\n    node [shape=box];\n");
+    for (i, area) in boxes.iter().enumerate() {
+        let rows = canvas.box_label(*area);
+        let rows: Vec<_> =
+            rows.iter().map(|row| row.replace('\\', "\\\\").replace('"', "\\\"")).collect();
+        let label = if rows.is_empty() { format!("box {}", i + 1) } else { rows.join("\\n") };
+        dot.push_str(&format!("    n{i} [label=\"{label}\"];\n"));
+    }
+    for (from, to) in canvas.edges(&boxes) {
+        dot.push_str(&format!("    n{from} -> n{to};\n"));
+    }
+    dot.push_str("}\n");
+    Ok(dot)
+}
+
+// the compact format starts with this, then a flags byte (1 for single_width), the number of
+// cells and runs of them: row, first column, glyph count, color, then each glyph's codepoint,
+// numbers as LEB128 varints, a run continues wherever the next glyph sits right after the last
+const BIN_MAGIC: &[u8; 4] = b"TDB1";
+
+// the named colors in the order their bytes give them, 0 is no color, then Rgb and Indexed
+const BIN_COLORS: [Color; 17] = [
+    Color::Reset,
+    Color::Black,
+    Color::Red,
+    Color::Green,
+    Color::Yellow,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::Gray,
+    Color::DarkGray,
+    Color::LightRed,
+    Color::LightGreen,
+    Color::LightYellow,
+    Color::LightBlue,
+    Color::LightMagenta,
+    Color::LightCyan,
+    Color::White,
+];
+
+fn push_varint(bytes: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn push_color(bytes: &mut Vec<u8>, fg: Option<Color>) {
+    match fg {
+        None => bytes.push(0),
+        Some(Color::Rgb(r, g, b)) => bytes.extend([18, r, g, b]),
+        Some(Color::Indexed(index)) => bytes.extend([19, index]),
+        Some(color) => {
+            let named = BIN_COLORS.iter().position(|named| *named == color).unwrap_or(0);
+            bytes.push(named as u8 + 1);
+        }
+    }
+}
+
+// the canvas in the compact format, much quicker to read back than text for huge drawings
+pub fn save_bin(canvas: &Canvas) -> Vec<u8> {
+    let mut cells: Vec<_> = canvas.cells.iter().collect();
+    cells.sort_by_key(|((x, y), _)| (*y, *x));
+    let mut bytes = BIN_MAGIC.to_vec();
+    bytes.push(canvas.single_width as u8);
+    push_varint(&mut bytes, canvas.cells.len() as u32);
+    let mut run: Vec<char> = Vec::new();
+    let mut start = (0, 0, None);
+    let mut next = None;
+    let flush = |bytes: &mut Vec<u8>, run: &mut Vec<char>, (x, y, fg): (i32, i32, _)| {
+        if run.is_empty() {
+            return;
+        }
+        push_varint(bytes, y as u32);
+        push_varint(bytes, x as u32);
+        push_varint(bytes, run.len() as u32);
+        push_color(bytes, fg);
+        for char in run.drain(..) {
+            push_varint(bytes, char as u32);
+        }
+    };
+    for (&(x, y), cell) in cells {
+        if next != Some((x, y)) || start.2 != cell.fg {
+            flush(&mut bytes, &mut run, start);
+            start = (x, y, cell.fg);
+        }
+        run.push(cell.char);
+        next = Some((x + canvas.advance(cell.char), y));
+    }
+    flush(&mut bytes, &mut run, start);
+    bytes
+}
+
+fn read_byte(bytes: &mut std::slice::Iter<u8>) -> Result<u8> {
+    bytes.next().copied().ok_or_else(|| anyhow::anyhow!("binary diagram is cut short"))
+}
+
+fn read_varint(bytes: &mut std::slice::Iter<u8>) -> Result<u32> {
+    let mut value = 0u32;
+    for shift in (0..35).step_by(7) {
+        let byte = read_byte(bytes)?;
+        value |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    anyhow::bail!("binary diagram has a number that is too long")
+}
+
+// a column or row, which save_bin never writes past i32::MAX
+fn read_coordinate(bytes: &mut std::slice::Iter<u8>) -> Result<i32> {
+    let value = read_varint(bytes)?;
+    i32::try_from(value).map_err(|_| anyhow::anyhow!("binary diagram has a bad position {value}"))
+}
+
+// a canvas from save_bin's format
+pub fn load_bin(bytes: &[u8]) -> Result<Canvas> {
+    let Some(rest) = bytes.strip_prefix(BIN_MAGIC.as_slice()) else {
+        anyhow::bail!("not a term-draw binary diagram");
+    };
+    let mut bytes = rest.iter();
+    let single_width = read_byte(&mut bytes)? & 1 == 1;
+    let mut canvas = Canvas { single_width, ..Canvas::default() };
+    // the cell count is only a hint, a file that lies about it still loads
+    canvas.cells.reserve(read_varint(&mut bytes)?.min(1 << 24) as usize);
+    while bytes.len() > 0 {
+        let y = read_coordinate(&mut bytes)?;
+        let mut x = read_coordinate(&mut bytes)?;
+        let count = read_varint(&mut bytes)?;
+        let fg = match read_byte(&mut bytes)? {
+            0 => None,
+            18 => {
+                let mut rgb = [0; 3];
+                for part in &mut rgb {
+                    *part = read_byte(&mut bytes)?;
+                }
+                Some(Color::Rgb(rgb[0], rgb[1], rgb[2]))
+            }
+            19 => Some(Color::Indexed(read_byte(&mut bytes)?)),
+            named => Some(*BIN_COLORS.get(named as usize - 1).unwrap_or(&Color::Reset)),
+        };
+        for _ in 0..count {
+            let codepoint = read_varint(&mut bytes)?;
+            let char = char::from_u32(codepoint)
+                .ok_or_else(|| anyhow::anyhow!("binary diagram has a bad glyph {codepoint:#x}"))?;
+            canvas.cells.insert((x, y), Cell { char, fg });
+            x = x
+                .checked_add(canvas.advance(char))
+                .ok_or_else(|| anyhow::anyhow!("binary diagram has a row running off the end"))?;
+        }
+    }
+    Ok(canvas)
+}
+
+// a png with one bitmap font cell per canvas cell, sized to the content's bounding box and
+// `margin`
+#[cfg(feature = "png")]
+pub fn export_png(canvas: &Canvas, margin: Margin) -> Result<Vec<u8>> {
+    let Some((left, top, right, bottom)) = canvas.bounds() else {
+        anyhow::bail!("diagram is empty");
+    };
+    let (left, top) = (left - margin.0, top - margin.1);
+    let (right, bottom) = (right + margin.2, bottom + margin.3);
+    let mut image = png::Image::new((right - left + 1) as usize, (bottom - top + 1) as usize);
+    for (pos, cell) in &canvas.cells {
+        image.draw_glyph((pos.0 - left) as usize, (pos.1 - top) as usize, cell.char, cell.fg);
+    }
+    Ok(image.encode())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn canvas(cells: &[((i32, i32), char)]) -> Canvas {
+        let mut canvas = Canvas::default();
+        for &(pos, char) in cells {
+            canvas.insert(pos, Cell { char, fg: None });
+        }
+        canvas
+    }
+
+    #[test]
+    fn table_glyphs_draws_shared_borders() {
+        let glyphs = table_glyphs((1, 1), (2, 3, 2, 1), &BoxStyle::default(), 4000).unwrap();
+        assert_eq!(glyphs[&(1, 1)], '╭');
+        assert_eq!(glyphs[&(4, 1)], '┬');
+        assert_eq!(glyphs[&(1, 3)], '├');
+        assert_eq!(glyphs[&(4, 3)], '┼');
+        assert_eq!(glyphs[&(10, 5)], '╯');
+        assert!(!glyphs.contains_key(&(2, 2)));
+        // 3 border rows of 10 cells and 2 rows with 4 walls each
+        assert_eq!(glyphs.len(), 3 * 10 + 2 * 4);
+    }
+
+    #[test]
+    fn table_glyphs_refuses_tables_past_the_limit() {
+        let style = BoxStyle::default();
+        assert!(table_glyphs((0, 0), (1, 1, i32::MAX, 1), &style, 4000).is_err());
+        assert!(table_glyphs((0, 0), (i32::MAX, 1, 1, 1), &style, 4000).is_err());
+        assert!(table_glyphs((0, 0), (100000, 100000, 8, 1), &style, 4000).is_err());
+        assert!(table_glyphs((3990, 0), (1, 1, 9, 1), &style, 4000).is_err());
+        assert!(table_glyphs((3990, 0), (1, 1, 8, 1), &style, 4000).is_ok());
+    }
+
+    #[test]
+    fn write_text_leaves_cells_under_spaces() {
+        let mut canvas = canvas(&[((0, 0), '│'), ((2, 0), '│'), ((0, 1), 'x')]);
+        canvas.write_text((0, 0), "a  b\n 界 c", None);
+        assert_eq!(canvas.row(0, &Glyphs::new()), "a │b");
+        assert_eq!(canvas.row(1, &Glyphs::new()), "x界 c");
+    }
+
+    #[test]
+    fn row_draws_the_overlay_over_the_cells() {
+        let canvas = canvas(&[((0, 0), 'a'), ((1, 0), 'b'), ((0, 1), 'c')]);
+        let overlay = Glyphs::from([((1, 0), '─'), ((4, 0), '▸')]);
+        assert_eq!(canvas.row(0, &overlay), "a─  ▸");
+        assert_eq!(canvas.row(1, &overlay), "c");
+        assert_eq!(canvas.row(2, &overlay), "");
+    }
+
+    #[test]
+    fn row_steps_over_the_right_half_of_wide_glyphs() {
+        let mut canvas = canvas(&[((0, 0), '界'), ((2, 0), 'x'), ((4, 0), '語')]);
+        assert_eq!(canvas.row(0, &Glyphs::new()), "界x 語");
+        canvas.single_width = true;
+        assert_eq!(canvas.row(0, &Glyphs::new()), "界 x 語");
+    }
+
+    #[test]
+    fn row_trims_plain_spaces_but_keeps_hard_ones() {
+        let plain = canvas(&[((0, 0), 'a'), ((1, 0), ' '), ((3, 0), ' ')]);
+        assert_eq!(plain.row(0, &Glyphs::new()), "a");
+        let hard = canvas(&[((0, 0), 'a'), ((2, 0), HARD_SPACE), ((4, 0), ' ')]);
+        assert_eq!(hard.row(0, &Glyphs::new()), "a \u{a0}");
+        assert_eq!(hard.rows().collect::<Vec<_>>(), ["a \u{a0}"]);
+    }
+}
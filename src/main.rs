@@ -1,318 +1,4899 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     io::{self, Stdout, BufWriter, Write},
-    time::Duration, cmp::{min, max}, fs::File,
+    process::{Command, Stdio},
+    sync::atomic::{AtomicBool, Ordering},
+    cmp::{min, max}, fs::{self, File},
+    path::{Path, PathBuf}, env, panic,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, Result};
 use crossterm::{
-    event::{self, Event, KeyCode},
-    execute,
-    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    cursor::{SetCursorStyle, Show},
+    event::{
+        self, DisableBracketedPaste, EnableBracketedPaste, Event, KeyCode, KeyEvent, KeyEventKind,
+        KeyModifiers, KeyboardEnhancementFlags, PopKeyboardEnhancementFlags,
+        PushKeyboardEnhancementFlags,
+    },
+    execute, style,
+    terminal::{
+        self, disable_raw_mode, enable_raw_mode, supports_keyboard_enhancement,
+        EnterAlternateScreen, LeaveAlternateScreen,
+    },
 };
 use ratatui::{prelude::*, widgets::*};
+use unicode_width::UnicodeWidthChar;
+use term_draw::{
+    arrow_glyphs, box_sides_glyphs, elbow, ellipse_glyphs, export_comment, export_dot, glyph_like,
+    glyph_sides, head_side, load_bin, opposite, render, save_bin, side, step, table_glyphs,
+    trim_trailing_spaces, BoxStyle, Canvas, Cell, Glyphs, Margin, DOWN, HARD_SPACE, LEFT, RIGHT,
+    UP,
+};
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 enum InputState {
     INSERT,
     BOX,
     ARROW,
+    SELECT,
+    ELLIPSE,
+    TRACE,
+}
+
+impl InputState {
+    const ALL: [InputState; 6] = [
+        InputState::INSERT,
+        InputState::BOX,
+        InputState::ARROW,
+        InputState::SELECT,
+        InputState::ELLIPSE,
+        InputState::TRACE,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            InputState::INSERT => "insert",
+            InputState::BOX => "box",
+            InputState::ARROW => "arrow",
+            InputState::SELECT => "select",
+            InputState::ELLIPSE => "ellipse",
+            InputState::TRACE => "trace",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+enum BoxOverlap {
+    #[default]
+    Overwrite,
+    // shared edges become junctions
+    Merge,
+    // overwrite, saying how many line cells were hit
+    Warn,
+}
+
+// where Alt-L puts a title along the top border
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+enum TitleAlign {
+    #[default]
+    Left,
+    // an odd number of spare cells leaves the extra one on the right
+    Center,
+    // or on the left
+    CenterRight,
+}
+
+#[derive(Clone, Default, Debug)]
+struct Config {
+    // every glyph advances one cell, for fonts that draw "wide" glyphs narrow
+    single_width: bool,
+    glyphs: BoxStyle,
+    // typing past the right edge carries the current word over to the next row
+    wrap: bool,
+    // box titles longer than the box get cut instead of widening the box
+    truncate_titles: bool,
+    title_align: TitleAlign,
+    // an arrow confirmed this close to an existing glyph ends on that glyph, 0 turns it off
+    snap_radius: i32,
+    // saved in place of empty cells, the screen keeps showing spaces
+    fill: Option<char>,
+    // blank cells around cropped exports as (left, top, right, bottom)
+    margin: Margin,
+    // Enter in INSERT mode lines the cursor up with the text on the row it leaves
+    auto_indent: bool,
+    // Enter in INSERT mode moves straight down instead of to the start of the next row
+    enter_keeps_column: bool,
+    // in INSERT mode Left and Right carry on across the ends of rows like a text editor
+    wrap_cursor: bool,
+    // the canvas grows past the window and the view follows the cursor
+    scroll: bool,
+    // typing moves the cursor down a row instead of right, for vertical labels
+    text_down: bool,
+    // the status bar above the canvas instead of below it
+    status_top: bool,
+    // a box is anchored where BOX mode is entered rather than where the first move starts from
+    anchor_on_enter: bool,
+    // in INSERT mode the cursor stops just past the last glyph of its row
+    bounded_cursor: bool,
+    // typing onto a line glyph is refused instead of punching a hole in the line
+    protect_lines: bool,
+    // starts every line of a comment export, `*` wraps it in `/* */`, None is `//`
+    comment_marker: Option<String>,
+    // `:name:` typed before the cursor expands to the text on Tab, from `mnemonic.name` keys
+    mnemonics: HashMap<String, String>,
+    // NO_COLOR is set or the terminal can't do color, emphasis falls back to modifiers
+    monochrome: bool,
+    // seconds between writing swap files of changed tabs, 0 turns autosave off
+    autosave: i32,
+    // swap files go under $XDG_CACHE_HOME/term-draw instead of next to the file
+    swap_in_cache: bool,
+    // swap files hold the cells as JSON instead of the saved text
+    swap_json: bool,
+    // saves drop the empty rows below the drawing
+    trim_blank_lines: bool,
+    // saves leave out the newline after the last row
+    no_final_newline: bool,
+    // a refused keystroke rings the terminal bell
+    bell: bool,
+    // a refused keystroke doesn't invert the status bar for a moment
+    no_flash: bool,
+    // the editor blinks the cursor itself while no keys come in, in every mode
+    blink: bool,
+    // saves end rows with \r\n, for Windows tools
+    crlf: bool,
+    // what the character map prompt in SELECT mode starts with, pairs like `*● o○`
+    char_map: String,
+    // what a confirmed box does where its border lands on other lines
+    box_overlap: BoxOverlap,
+    // the key that finishes a shape outside INSERT mode, None is Enter
+    confirm_key: Option<(KeyModifiers, KeyCode)>,
+    // the key that quits from any mode, None is Ctrl-Q
+    quit_key: Option<(KeyModifiers, KeyCode)>,
+    // a confirm or paste replacing at least this many glyphs asks first, 0 never asks
+    overwrite_threshold: i32,
+    // widest and tallest canvas in cells, files past it are refused, None is MAX_CANVAS
+    max_canvas: Option<i32>,
+    // columns between the tab stops a tab in an opened file moves to, None is TAB_WIDTH
+    tab_width: Option<i32>,
+    // text saves start with a UTF-8 byte order mark
+    bom: bool,
+    // text saves start with a `# term-draw:` line holding the canvas size and cursor
+    write_header: bool,
+    // what an arrow that never leaves its first cell draws, None is `◆`
+    arrow_point: Option<char>,
+    no_arrow_point: bool,
+    // saves put the tabs an opened file had back wherever the columns they covered are still empty
+    keep_tabs: bool,
+    // the diagnostics dump ends with the drawing itself, left out by default
+    diagnostics_contents: bool,
+    // the canvas size asked for on the command line instead of the window's, None follows it
+    width: Option<i32>,
+    height: Option<i32>,
+}
+
+const MAX_CANVAS: i32 = 4000;
+
+const TAB_WIDTH: i32 = 8;
+
+// how long the status bar stays inverted after a refused action
+const FLASH_TIME: Duration = Duration::from_millis(150);
+
+// how long the cursor stays shown, then hidden, with blink on
+const BLINK_TIME: Duration = Duration::from_millis(500);
+
+// the status bar spinner while a slow task runs, a frame per SPIN_TIME
+const SPINNER: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+const SPIN_TIME: Duration = Duration::from_millis(100);
+
+// work that can take a moment, run on a thread so the editor keeps drawing
+struct Task {
+    label: String,
+    started: Instant,
+    handle: JoinHandle<Finished>,
+}
+
+enum Finished {
+    // the tab the task ran for and what to tell about it
+    #[cfg(feature = "png")]
+    Exported(usize, String),
+    Opened(PathBuf, Result<Box<GlobalState>>),
+}
+
+impl Config {
+    // `key = value` lines from $XDG_CONFIG_HOME/term-draw/config, `#` starts a comment
+    fn load() -> Result<Config> {
+        let mut config = Config::default();
+        let Some(path) = config_path() else {
+            return Ok(config);
+        };
+        if !path.exists() {
+            return Ok(config);
+        }
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("cannot read {}", path.display()))?;
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                anyhow::bail!("{}:{}: expected `key = value`", path.display(), number + 1);
+            };
+            config
+                .set(key.trim(), value.trim())
+                .with_context(|| format!("{}:{}", path.display(), number + 1))?;
+        }
+        Ok(config)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "single_width" => self.single_width = parse_bool(value)?,
+            "glyphs" => self.glyphs = BoxStyle::named(value)?,
+            "weight" => self.glyphs = BoxStyle::weighted(value)?,
+            "wrap" => self.wrap = parse_bool(value)?,
+            "auto_indent" => self.auto_indent = parse_bool(value)?,
+            "enter_keeps_column" => self.enter_keeps_column = parse_bool(value)?,
+            "wrap_cursor" => self.wrap_cursor = parse_bool(value)?,
+            "scroll" => self.scroll = parse_bool(value)?,
+            "bounded_cursor" => self.bounded_cursor = parse_bool(value)?,
+            "protect_lines" => self.protect_lines = parse_bool(value)?,
+            "text_direction" => {
+                self.text_down = match value {
+                    "right" => false,
+                    "down" => true,
+                    _ => anyhow::bail!("expected right or down, got {value}"),
+                };
+            }
+            "status_bar" => {
+                self.status_top = match value {
+                    "bottom" => false,
+                    "top" => true,
+                    _ => anyhow::bail!("expected top or bottom, got {value}"),
+                };
+            }
+            "box_anchor" => {
+                self.anchor_on_enter = match value {
+                    "first_move" => false,
+                    "enter" => true,
+                    _ => anyhow::bail!("expected first_move or enter, got {value}"),
+                };
+            }
+            "truncate_titles" => self.truncate_titles = parse_bool(value)?,
+            "title_align" => {
+                self.title_align = match value {
+                    "left" => TitleAlign::Left,
+                    "center" => TitleAlign::Center,
+                    "center_right" => TitleAlign::CenterRight,
+                    _ => anyhow::bail!("expected left, center or center_right, got {value}"),
+                };
+            }
+            "fill" => {
+                let mut chars = value.chars();
+                let (Some(fill), None) = (chars.next(), chars.next()) else {
+                    anyhow::bail!("expected a single character, got {value}");
+                };
+                self.fill = Some(fill).filter(|fill| *fill != ' ');
+            }
+            "snap_radius" => {
+                self.snap_radius = value
+                    .parse()
+                    .ok()
+                    .filter(|radius| *radius >= 0)
+                    .with_context(|| format!("expected a cell count, got {value}"))?;
+            }
+            "char_map" => {
+                parse_char_map(value)?;
+                self.char_map = value.to_string();
+            }
+            "margin" => {
+                let cells: Vec<i32> = value
+                    .split_whitespace()
+                    .map(|cells| cells.parse().ok().filter(|cells| *cells >= 0))
+                    .collect::<Option<_>>()
+                    .with_context(|| format!("expected cell counts, got {value}"))?;
+                self.margin = match cells[..] {
+                    [all] => (all, all, all, all),
+                    [across, down] => (across, down, across, down),
+                    [left, top, right, bottom] => (left, top, right, bottom),
+                    _ => anyhow::bail!("expected 1, 2 or 4 cell counts, got {value}"),
+                };
+            }
+            "comment_marker" => self.comment_marker = Some(value.to_string()),
+            "trim_blank_lines" => self.trim_blank_lines = parse_bool(value)?,
+            "final_newline" => self.no_final_newline = !parse_bool(value)?,
+            "bell" => self.bell = parse_bool(value)?,
+            "blink" => self.blink = parse_bool(value)?,
+            "flash" => self.no_flash = !parse_bool(value)?,
+            "line_endings" => {
+                self.crlf = match value {
+                    "lf" => false,
+                    "crlf" => true,
+                    _ => anyhow::bail!("expected lf or crlf, got {value}"),
+                };
+            }
+            "confirm_key" => self.confirm_key = Some(parse_key(value)?),
+            "quit_key" => self.quit_key = Some(parse_key(value)?),
+            "box_overlap" => {
+                self.box_overlap = match value {
+                    "overwrite" => BoxOverlap::Overwrite,
+                    "merge" => BoxOverlap::Merge,
+                    "warn" => BoxOverlap::Warn,
+                    _ => anyhow::bail!("expected overwrite, merge or warn, got {value}"),
+                };
+            }
+            "overwrite_threshold" => {
+                self.overwrite_threshold = value
+                    .parse()
+                    .ok()
+                    .filter(|cells| *cells >= 0)
+                    .with_context(|| format!("expected a cell count, got {value}"))?;
+            }
+            "tab_width" => {
+                let width = value.parse().ok().filter(|columns| *columns > 0);
+                let width = width.with_context(|| format!("expected a column count, got {value}"))?;
+                self.tab_width = Some(width);
+            }
+            "keep_tabs" => self.keep_tabs = parse_bool(value)?,
+            "diagnostics_contents" => self.diagnostics_contents = parse_bool(value)?,
+            "arrow_point" => {
+                let mut chars = value.chars();
+                (self.arrow_point, self.no_arrow_point) = match (chars.next(), chars.next()) {
+                    _ if value == "none" => (None, true),
+                    (Some(char), None) => (Some(char), false),
+                    _ => anyhow::bail!("expected one character or none, got {value}"),
+                };
+            }
+            "write_header" => self.write_header = parse_bool(value)?,
+            "bom" => self.bom = parse_bool(value)?,
+            "autosave" => {
+                self.autosave = value
+                    .parse()
+                    .ok()
+                    .filter(|seconds| *seconds >= 0)
+                    .with_context(|| format!("expected a number of seconds, got {value}"))?;
+            }
+            "swap_dir" => {
+                self.swap_in_cache = match value {
+                    "beside" => false,
+                    "cache" => true,
+                    _ => anyhow::bail!("expected beside or cache, got {value}"),
+                };
+            }
+            "swap_format" => {
+                self.swap_json = match value {
+                    "txt" => false,
+                    "json" => true,
+                    _ => anyhow::bail!("expected txt or json, got {value}"),
+                };
+            }
+            "max_canvas" => {
+                let cells = value
+                    .parse()
+                    .ok()
+                    .filter(|cells| *cells > 0)
+                    .with_context(|| format!("expected a cell count, got {value}"))?;
+                self.max_canvas = Some(cells);
+            }
+            // `\n` in the value starts a new row, for small stencils
+            _ if key.starts_with("mnemonic.") => {
+                let name = &key["mnemonic.".len()..];
+                if name.is_empty() || !name.chars().all(mnemonic_char) {
+                    anyhow::bail!("expected letters, digits, - or _ in the name, got {name}");
+                }
+                self.mnemonics.insert(name.to_string(), value.replace("\\n", "\n"));
+            }
+            _ => anyhow::bail!("unknown setting {key}"),
+        }
+        Ok(())
+    }
+
+    fn max_canvas(&self) -> i32 {
+        self.max_canvas.unwrap_or(MAX_CANVAS)
+    }
+
+    fn tab_width(&self) -> i32 {
+        self.tab_width.unwrap_or(TAB_WIDTH)
+    }
+
+    fn arrow_point(&self) -> Option<char> {
+        (!self.no_arrow_point).then_some(self.arrow_point.unwrap_or('◆'))
+    }
+}
+
+fn config_path() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("term-draw").join("config"))
+}
+
+// files opened or saved lately, newest first, kept next to the config
+fn recent_path() -> Option<PathBuf> {
+    config_path().map(|path| path.with_file_name("recent"))
+}
+
+const RECENT_LIMIT: usize = 20;
+
+fn read_recent() -> Vec<PathBuf> {
+    let Some(contents) = recent_path().and_then(|path| fs::read_to_string(path).ok()) else {
+        return Vec::new();
+    };
+    contents.lines().filter(|line| !line.is_empty()).map(PathBuf::from).collect()
+}
+
+// moves `path` to the top of the recent files, a list that can't be written is just not kept
+fn remember_recent(path: &Path) {
+    // a new file gets on the list when it is first saved
+    let Some(list) = recent_path().filter(|_| path.exists()) else {
+        return;
+    };
+    let absolute = env::current_dir().map_or(path.to_path_buf(), |dir| dir.join(path));
+    let mut recent = read_recent();
+    recent.retain(|known| *known != absolute);
+    recent.insert(0, absolute);
+    recent.truncate(RECENT_LIMIT);
+    let contents: String = recent.iter().map(|path| format!("{}\n", path.display())).collect();
+    if let Some(dir) = list.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    let _ = fs::write(list, contents);
+}
+
+// recorded takes saved under a name, kept next to the config as `[name]` followed by one
+// action per line
+fn macros_path() -> Option<PathBuf> {
+    config_path().map(|path| path.with_file_name("macros"))
+}
+
+// every saved macro in file order, lines that are no action are left out
+fn read_macros() -> Vec<(String, Vec<Action>)> {
+    let Some(contents) = macros_path().and_then(|path| fs::read_to_string(path).ok()) else {
+        return Vec::new();
+    };
+    let mut macros: Vec<(String, Vec<Action>)> = Vec::new();
+    for line in contents.lines() {
+        if let Some(name) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+            macros.push((name.to_string(), Vec::new()));
+        } else if let (Some(last), Some(action)) = (macros.last_mut(), parse_action(line)) {
+            last.1.push(action);
+        }
+    }
+    macros
+}
+
+// adds the macro or replaces the one of the same name
+fn write_macro(name: &str, actions: &[Action]) -> Result<()> {
+    let path = macros_path().context("no config directory for macros")?;
+    let mut macros = read_macros();
+    macros.retain(|(known, _)| known != name);
+    macros.push((name.to_string(), actions.to_vec()));
+    let mut contents = String::new();
+    for (name, actions) in &macros {
+        contents.push_str(&format!("[{name}]\n"));
+        for action in actions {
+            contents.push_str(&format!("{action:?}\n"));
+        }
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("cannot create {}", dir.display()))?;
+    }
+    fs::write(&path, contents).with_context(|| format!("cannot write {}", path.display()))
+}
+
+// reads back an action as `{:?}` writes it, only ones a key can give are known
+fn parse_action(line: &str) -> Option<Action> {
+    if let Some(literal) = line.strip_prefix("Insert(").and_then(|rest| rest.strip_suffix(')')) {
+        let inner = literal.strip_prefix('\'')?.strip_suffix('\'')?;
+        let mut chars = inner.chars();
+        return match (chars.next()?, chars.next(), chars.as_str()) {
+            (char, None, _) => Some(char),
+            ('\\', Some('t'), "") => Some('\t'),
+            ('\\', Some('u'), hex) => hex
+                .strip_prefix('{')
+                .and_then(|hex| hex.strip_suffix('}'))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .and_then(char::from_u32),
+            ('\\', Some(escaped @ ('\\' | '\'' | '"')), "") => Some(escaped),
+            _ => None,
+        }
+        .map(Action::Insert);
+    }
+    let keyed = default_keymap().into_values();
+    let mode_keys = MODE_KEYS.iter().map(|(_, _, action)| *action);
+    let factors = (2..=9).map(Action::ScaleFactor);
+    let mut known = keyed.chain(mode_keys).chain(factors).chain([Action::Confirm]);
+    known.find(|action| format!("{action:?}") == line)
 }
 
+fn mnemonic_char(char: char) -> bool {
+    char.is_alphanumeric() || char == '-' || char == '_'
+}
+
+// a key like `enter`, `space`, `tab`, `;` or `ctrl-k`, letters are taken as typed
+fn parse_key(value: &str) -> Result<(KeyModifiers, KeyCode)> {
+    let mut modifiers = KeyModifiers::NONE;
+    let mut rest = value;
+    loop {
+        if let Some(tail) = rest.strip_prefix("ctrl-") {
+            modifiers |= KeyModifiers::CONTROL;
+            rest = tail;
+        } else if let Some(tail) = rest.strip_prefix("alt-") {
+            modifiers |= KeyModifiers::ALT;
+            rest = tail;
+        } else {
+            break;
+        }
+    }
+    let mut chars = rest.chars();
+    let code = match (rest, chars.next(), chars.next()) {
+        ("enter", _, _) => KeyCode::Enter,
+        ("space", _, _) => KeyCode::Char(' '),
+        ("tab", _, _) => KeyCode::Tab,
+        (_, Some(char), None) => KeyCode::Char(char),
+        _ => anyhow::bail!("expected a key like enter, space, tab, ; or ctrl-k, got {value}"),
+    };
+    Ok((modifiers, code))
+}
+
+fn parse_bool(value: &str) -> Result<bool> {
+    match value {
+        "true" | "yes" | "on" => Ok(true),
+        "false" | "no" | "off" => Ok(false),
+        _ => anyhow::bail!("expected true or false, got {value}"),
+    }
+}
+
+const PALETTE: [Option<Color>; 7] = [
+    None,
+    Some(Color::Red),
+    Some(Color::Green),
+    Some(Color::Yellow),
+    Some(Color::Blue),
+    Some(Color::Magenta),
+    Some(Color::Cyan),
+];
+
+// the colors Alt-O offers, in rows of PICKER_ROW
+const PICKER: [Option<Color>; 17] = [
+    None,
+    Some(Color::Black),
+    Some(Color::Red),
+    Some(Color::Green),
+    Some(Color::Yellow),
+    Some(Color::Blue),
+    Some(Color::Magenta),
+    Some(Color::Cyan),
+    Some(Color::Gray),
+    Some(Color::DarkGray),
+    Some(Color::LightRed),
+    Some(Color::LightGreen),
+    Some(Color::LightYellow),
+    Some(Color::LightBlue),
+    Some(Color::LightMagenta),
+    Some(Color::LightCyan),
+    Some(Color::White),
+];
+const PICKER_ROW: usize = 9;
+
+// the exports the preview pane can show, in the order Alt-V steps through them
+#[derive(Clone, Copy, PartialEq)]
+enum ExportView {
+    Ascii,
+    Cropped,
+    Comment,
+}
+
+#[derive(Clone)]
 struct GlobalState {
+    config: Config,
+    glyphs: BoxStyle,
     color: bool,
-    should_quit: bool,
-    start_pos: (u16, u16),
-    prev_pos: (u16, u16),
-    current_pos: (u16, u16),
-    diagram: HashMap<(u16, u16), char>,
-    preview: HashMap<(u16, u16), char>,
-    window_size: (u16, u16),
+    show_dots: bool,
+    // rows and columns with a faint guide line across the empty cells, screen only
+    guide_rows: Vec<i32>,
+    guide_columns: Vec<i32>,
+    // extra INSERT mode cursors left behind with Alt-E, they stay put until the first edit and
+    // from then on type and move along with the cursor
+    cursors: Vec<(i32, i32)>,
+    cursors_follow: bool,
+    // row numbers down the left edge, screen only
+    line_numbers: bool,
+    // a border around the canvas and row numbers, screen only
+    framed: bool,
+    // diagram statistics in a corner of the canvas, screen only
+    show_stats: bool,
+    // the cursor's column and row in a label beside it, screen only
+    show_coords: bool,
+    // the cursor's row and column shaded across the canvas, screen only
+    crosshair: bool,
+    // an export shown beside the canvas as it would be written, screen only
+    export_view: Option<ExportView>,
+    start_pos: (i32, i32),
+    prev_pos: (i32, i32),
+    current_pos: (i32, i32),
+    diagram: Canvas,
+    // color given to newly drawn cells
+    active_color: Option<Color>,
+    preview: HashMap<(i32, i32), char>,
+    // ARROW mode bends, the arrow runs start_pos -> waypoints -> current_pos
+    waypoints: Vec<(i32, i32)>,
+    // sides the TRACE mode trail joins in each cell it went through, a half line reads back
+    // from its glyph as a whole one
+    trace: HashMap<(i32, i32), u8>,
+    // where the tabs of the opened file started, with keep_tabs, the cells stay empty for them
+    tabs: HashSet<(i32, i32)>,
+    // the trace carries on an arrow, its head goes back on the tip when the trace ends
+    extend_head: bool,
+    // BOX mode grows the box symmetrically around start_pos
+    box_from_center: bool,
+    // the box has its anchor, until then moving or Shift and an arrow only moves the cursor
+    anchored: bool,
+    // BOX and ELLIPSE mode keep two columns per row so the shape looks square on screen
+    box_square: bool,
+    // BOX mode draws only these sides of the border, UP for the top one and so on
+    box_sides: u8,
+    // how many times wider or taller a SELECT mode stretch makes the selection
+    scale: i32,
+    // text stamped at the cursor in one go
+    brush: String,
+    // the glyph Alt-F picked off the canvas, stamped and filled with in place of the brush until
+    // the brush is set again
+    active_char: Option<char>,
+    // read-only rectangles as (left, top, right, bottom), edits touching them are undone
+    locked: Vec<(i32, i32, i32, i32)>,
+    // the selection x marked to swap with the next one
+    swap_mark: Option<(i32, i32, i32, i32)>,
+    window_size: (i32, i32),
+    // canvas position shown in the top-left corner of the window
+    viewport: (i32, i32),
     input_state: InputState,
+    path: PathBuf,
+    dirty: bool,
+    // written at least once since it was opened, for the exit summary
+    saved: bool,
+    message: String,
+    // the last action was refused, the workspace turns it into a flash or bell
+    blocked: bool,
+    // the committed cells as drawn last frame, rebuilt only after they change
+    canvas: String,
+    canvas_stale: bool,
+    // the drawing before each edit with the cursor and mode it was made in, newest last
+    undo: Vec<UndoStep>,
+    // where in undo the edits being grouped by Ctrl-W start
+    group: Option<usize>,
 }
 
+type UndoStep = (Canvas, (i32, i32), InputState);
+
+const UNDO_LIMIT: usize = 200;
+
 impl Default for GlobalState {
     fn default() -> GlobalState {
         GlobalState {
+            config: Config::default(),
+            glyphs: BoxStyle::default(),
             color: false,
-            should_quit: false,
+            show_dots: false,
+            guide_rows: Vec::new(),
+            guide_columns: Vec::new(),
+            cursors: Vec::new(),
+            cursors_follow: false,
+            line_numbers: false,
+            framed: false,
+            show_stats: false,
+            show_coords: false,
+            crosshair: false,
+            export_view: None,
             start_pos: (0, 0),
             prev_pos: (0, 0),
             current_pos: (0, 0),
-            diagram: HashMap::new(),
+            diagram: Canvas::new(),
+            active_color: None,
             preview: HashMap::new(),
+            waypoints: Vec::new(),
+            trace: HashMap::new(),
+            tabs: HashSet::new(),
+            extend_head: false,
+            box_from_center: false,
+            anchored: false,
+            box_square: false,
+            box_sides: UP | DOWN | LEFT | RIGHT,
+            scale: 2,
+            brush: String::new(),
+            active_char: None,
+            locked: Vec::new(),
+            swap_mark: None,
             window_size: (0, 0),
+            viewport: (0, 0),
             input_state: InputState::INSERT,
+            path: PathBuf::from("output.txt"),
+            dirty: false,
+            saved: false,
+            message: String::new(),
+            blocked: false,
+            canvas: String::new(),
+            canvas_stale: true,
+            undo: Vec::new(),
+            group: None,
         }
     }
 }
 
-fn main() -> Result<()> {
-    let mut terminal = setup_terminal().context("setup failed")?;
-    let mut global_state = GlobalState::default();
-    run(&mut terminal, &mut global_state).context("app loop failed")?;
-    restore_terminal(&mut terminal).context("restore terminal failed")?;
-    Ok(())
-}
-
-fn setup_terminal() -> Result<Terminal<CrosstermBackend<Stdout>>> {
-    let mut stdout = io::stdout();
-    enable_raw_mode().context("failed to enable raw mode")?;
-    execute!(stdout, EnterAlternateScreen).context("unable to enter alternate screen")?;
-    Terminal::new(CrosstermBackend::new(stdout)).context("creating terminal failed")
-}
+impl GlobalState {
+    fn new(config: &Config) -> GlobalState {
+        let mut global_state = GlobalState {
+            config: config.clone(),
+            glyphs: config.glyphs.clone().with_point(config.arrow_point()),
+            ..GlobalState::default()
+        };
+        global_state.diagram.single_width = config.single_width;
+        global_state
+    }
 
-fn restore_terminal(terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> Result<()> {
-    disable_raw_mode().context("failed to disable raw mode")?;
-    execute!(terminal.backend_mut(), LeaveAlternateScreen)
-        .context("unable to switch to main screen")?;
-    terminal.show_cursor().context("unable to show cursor")
-}
-
-fn generate_output(global_state: &mut GlobalState) -> String { 
-    let size = global_state.window_size;
-    let mut chars = String::new();
-    for i in 0..size.1 {
-        for j in 0..size.0 {
-            if let Some(char) = global_state.preview.get(&(j as u16, i as u16)) {
-                chars.push(*char);
-            } else if let Some(char) = global_state.diagram.get(&(j as u16, i as u16)) {
-                chars.push(*char);
-            } else {
-                chars.push(' ');
+    fn from_file(path: &Path, config: &Config) -> Result<GlobalState> {
+        let mut global_state = GlobalState {
+            path: path.to_path_buf(),
+            ..GlobalState::new(config)
+        };
+        let max = config.max_canvas();
+        let too_big = |what: &str| {
+            anyhow::anyhow!(
+                "{} has more than {max} {what}, not opening it as a diagram \
+                 (raise max_canvas in the config if it is one)",
+                path.display()
+            )
+        };
+        // a missing file is a new diagram that gets created on save
+        if path.exists() && is_binary(path) {
+            let bytes = fs::read(path).with_context(|| format!("cannot read {}", path.display()))?;
+            let mut diagram =
+                load_bin(&bytes).with_context(|| format!("cannot load {}", path.display()))?;
+            match diagram.bounds() {
+                Some((left, top, _, _)) if left < 0 || top < 0 => {
+                    anyhow::bail!("{} has cells off the top or left of the canvas", path.display())
+                }
+                Some((_, _, _, bottom)) if bottom >= max => return Err(too_big("rows")),
+                Some((_, _, right, _)) if right >= max => return Err(too_big("columns")),
+                _ => {}
+            }
+            diagram.single_width = config.single_width;
+            global_state.diagram = diagram;
+        } else if path.exists() {
+            let contents = fs::read_to_string(path)
+                .with_context(|| format!("cannot read {}", path.display()))?;
+            // \r\n and old Mac \r both end a row, a \r left in a cell would draw as garbage
+            let mut contents = contents.replace("\r\n", "\n").replace('\r', "\n");
+            // a byte order mark only says the file is UTF-8
+            if let Some(rest) = contents.strip_prefix('\u{FEFF}') {
+                contents = rest.to_string();
+            }
+            if let Some(rest) = contents.strip_prefix(HEADER) {
+                let (header, rest) = rest.split_once('\n').unwrap_or((rest, ""));
+                read_header(&mut global_state, header);
+                contents = rest.to_string();
+            }
+            if contents.lines().count() > max as usize {
+                return Err(too_big("rows"));
+            }
+            let (mut replaced, mut dropped) = (0, 0);
+            for (y, line) in contents.lines().enumerate() {
+                let mut x = 0;
+                for char in line.chars() {
+                    if x >= max {
+                        return Err(too_big("columns"));
+                    }
+                    if char == '\t' {
+                        if config.keep_tabs {
+                            global_state.tabs.insert((x, y as i32));
+                        }
+                        let tab = config.tab_width();
+                        x = (x / tab + 1) * tab;
+                        continue;
+                    }
+                    // a zero width glyph such as a combining mark or a BOM would be drawn over
+                    // its neighbour and a control character has no glyph, both shift the grid
+                    if char.width() == Some(0) {
+                        dropped += 1;
+                        continue;
+                    }
+                    let char = if char.is_control() {
+                        replaced += 1;
+                        '\u{FFFD}'
+                    } else {
+                        char
+                    };
+                    // the fill character reads back as empty
+                    if char != ' ' && Some(char) != config.fill {
+                        global_state.diagram.insert((x, y as i32), Cell { char, fg: None });
+                    }
+                    x += global_state.advance(char);
+                }
+            }
+            let mut cleaned = Vec::new();
+            if replaced > 0 {
+                cleaned.push(format!("{replaced} control characters shown as \u{FFFD}"));
+            }
+            if dropped > 0 {
+                cleaned.push(format!("{dropped} zero width characters left out"));
             }
+            global_state.message = cleaned.join(", ");
         }
-        chars.push('\n');
+        read_sidecar(&mut global_state);
+        let swap = swap_path(&global_state);
+        if swap.exists() {
+            global_state.message = format!("autosave from an earlier session: {}", swap.display());
+        }
+        Ok(global_state)
+    }
+
+    // how far the cursor can go, the window unless scrolling lets the canvas grow past it
+    fn canvas_size(&self) -> (i32, i32) {
+        let max = self.config.max_canvas();
+        let open = if self.config.scroll { (max, max) } else { self.window_size };
+        let width = self.config.width.unwrap_or(open.0);
+        let height = self.config.height.unwrap_or(open.1);
+        (width.min(max), height.min(max))
+    }
+
+    // the canvas the diagram is laid out for, --width and --height or else the window
+    fn page_size(&self) -> (i32, i32) {
+        (
+            self.config.width.unwrap_or(self.window_size.0),
+            self.config.height.unwrap_or(self.window_size.1),
+        )
+    }
+
+    // the column just past the last glyph on a row, 0 for an empty row
+    fn row_end(&self, row: i32) -> i32 {
+        self.diagram
+            .iter()
+            .filter(|((_, y), _)| *y == row)
+            .map(|((x, _), cell)| x + self.advance(cell.char))
+            .max()
+            .unwrap_or(0)
+    }
+
+    // 1 on the last column (or row, typing downwards) of the canvas, 2 past it, where INSERT
+    // has nowhere to put a glyph, 0 elsewhere or in the other modes
+    fn at_edge(&self) -> i32 {
+        if self.input_state != InputState::INSERT {
+            return 0;
+        }
+        let (x, y) = self.current_pos;
+        let (width, height) = self.canvas_size();
+        let (pos, end) = if self.config.text_down { (y, height) } else { (x, width) };
+        (pos + 2 - end).clamp(0, 2)
+    }
+
+    // with bounded_cursor, INSERT keeps the cursor out of the empty space right of the text
+    fn bounded(&self) -> bool {
+        self.config.bounded_cursor && self.input_state == InputState::INSERT
+    }
+
+    fn wraps(&self) -> bool {
+        self.config.wrap_cursor && self.input_state == InputState::INSERT
+    }
+
+    // the last column wrap_cursor lets the cursor reach on a row
+    fn line_end(&self, row: i32) -> i32 {
+        if self.bounded() {
+            self.row_end(row)
+        } else {
+            self.canvas_size().0 - 1
+        }
+    }
+
+    // scrolls just enough to bring the cursor into view
+    fn follow_cursor(&mut self) {
+        let viewport = if self.config.scroll {
+            let (x, y) = self.current_pos;
+            let (width, height) = (self.window_size.0.max(1), self.window_size.1.max(1));
+            (
+                self.viewport.0.clamp(x - width + 1, x).max(0),
+                self.viewport.1.clamp(y - height + 1, y).max(0),
+            )
+        } else {
+            (0, 0)
+        };
+        if viewport != self.viewport {
+            self.viewport = viewport;
+            self.canvas_stale = true;
+        }
+    }
+
+    // scrolls so the cursor is in the middle of the window, as near as the top-left edge allows
+    fn center_on_cursor(&mut self) {
+        let (x, y) = self.current_pos;
+        let viewport = ((x - self.window_size.0 / 2).max(0), (y - self.window_size.1 / 2).max(0));
+        if viewport != self.viewport {
+            self.viewport = viewport;
+            self.canvas_stale = true;
+        }
+    }
+
+    // what stamping and filling use, the picked glyph over the brush
+    fn drawing_text(&self) -> String {
+        self.active_char.map_or_else(|| self.brush.clone(), String::from)
+    }
+
+    // preview wins over committed content
+    fn cell_at(&self, pos: (i32, i32)) -> Option<char> {
+        self.preview.get(&pos).copied().or_else(|| self.diagram.get(&pos).map(|cell| cell.char))
+    }
+
+    // a row as it shows on screen, the shape being drawn over the committed cells
+    fn row_text(&self, row: i32) -> String {
+        self.diagram.row(row, &self.preview)
+    }
+
+    // every shape starts fresh from the cursor, whatever mode came before
+    fn enter_mode(&mut self, input_state: InputState) {
+        self.input_state = input_state;
+        self.start_pos = self.current_pos;
+        self.prev_pos = self.current_pos;
+        self.anchored = self.config.anchor_on_enter;
+        self.extend_head = false;
+        self.waypoints.clear();
+        self.trace.clear();
+        self.preview.clear();
+    }
+
+    // the SELECT mode rectangle as (left, top, right, bottom)
+    fn selection(&self) -> (i32, i32, i32, i32) {
+        let (start, current) = (self.start_pos, self.current_pos);
+        (
+            min(start.0, current.0),
+            min(start.1, current.1),
+            max(start.0, current.0),
+            max(start.1, current.1),
+        )
+    }
+
+    fn undo_step(&self) -> UndoStep {
+        (self.diagram.clone(), self.current_pos, self.input_state)
+    }
+
+    // keeps `step` if the drawing has changed since it was taken
+    fn push_undo(&mut self, step: UndoStep) {
+        if step.0 == self.diagram {
+            return;
+        }
+        if self.undo.len() == UNDO_LIMIT {
+            self.undo.remove(0);
+            self.group = self.group.map(|start| start.saturating_sub(1));
+        }
+        self.undo.push(step);
+    }
+
+    fn colored(&self, char: char) -> Cell {
+        Cell { char, fg: self.active_color }
+    }
+
+    // rectangle around the committed content as (left, top, right, bottom)
+    fn bounds(&self) -> Option<(i32, i32, i32, i32)> {
+        self.diagram.bounds()
+    }
+
+    // cells taken up by a glyph on screen
+    fn advance(&self, char: char) -> i32 {
+        self.diagram.advance(char)
     }
 
-    return chars;
+    fn title(&self) -> String {
+        let name = match self.path.file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => self.path.display().to_string(),
+        };
+        if self.dirty {
+            format!("{name}*")
+        } else {
+            name
+        }
+    }
 }
 
-fn run(
-    terminal: &mut Terminal<CrosstermBackend<Stdout>>,
-    mut global_state: &mut GlobalState,
-) -> Result<()> {
-    let cursor_pos = terminal
-        .get_cursor()
-        .context("cannot get cursor position")?;
-    let terminal_size = terminal.size().context("cannot get terminal size")?;
-    loop {
-        global_state.window_size = (terminal_size.width, terminal_size.height);
-        // draw frame
-        let completed_frame = terminal.draw(|frame| {
-            let mut style = Style::default();
-            if global_state.color {
-                style = style.fg(Color::Yellow);
-            }
-
-            let chars = generate_output(global_state);
-            let text = Paragraph::new(chars).style(style);
-            frame.set_cursor(global_state.current_pos.0, global_state.current_pos.1);
-            frame.render_widget(text, terminal_size);
-        })?;
-        // get user input
-        global_state = process_input(global_state)?;
-        if global_state.should_quit {
-            break;
+// everything a key can do, so bindings live in one table instead of the input handler
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Action {
+    Quit,
+    // drops the shape being drawn or the extra cursors, quits when there is nothing to drop
+    Cancel,
+    SaveAndQuit,
+    ForceQuit,
+    Save,
+    SaveAll,
+    ToggleRecording,
+    Replay,
+    SaveMacro,
+    RunMacro,
+    NextTab,
+    PrevTab,
+    BoxMode,
+    ArrowMode,
+    SelectMode,
+    EllipseMode,
+    TraceMode,
+    // carries on the line or arrow ending at the cursor in TRACE mode
+    ExtendLine,
+    ToggleDots,
+    ToggleGuideRow,
+    ToggleGuideColumn,
+    ClearGuides,
+    ToggleCursor,
+    ToggleLineNumbers,
+    ToggleFrame,
+    CenterView,
+    ToggleStats,
+    ToggleCoords,
+    ToggleCrosshair,
+    CycleExportView,
+    // writes the editor's state next to the file to attach to a bug report
+    Diagnostics,
+    // says what the row under the cursor holds, as drawn
+    ShowRow,
+    Undo,
+    FitBox,
+    MoveAnchor(i32, i32),
+    // starts grouping edits, or ends it and folds them into one undo step
+    GroupUndo,
+    // moves the drawing to the top-left and sizes the canvas to it plus the margin
+    FitContent,
+    // grows the canvas by (columns, rows), shrinks it for negative ones
+    ResizeCanvas(i32, i32),
+    Copy,
+    Paste,
+    SystemCopy,
+    SystemPaste,
+    CycleColor,
+    ColorPicker,
+    #[cfg(feature = "png")]
+    ExportPng,
+    Insert(char),
+    Backspace,
+    AddWaypoint,
+    RemoveWaypoint,
+    ToggleBoxCenter,
+    ToggleBoxSquare,
+    ToggleBoxSide(u8),
+    SetBrush,
+    SetTitle,
+    // asks for a column, row and text and writes the text there, the cursor stays put
+    PlaceText,
+    QuickOpen,
+    Table,
+    MapChars,
+    Stamp,
+    PickBrush,
+    ScaleFactor(i32),
+    StretchWide,
+    StretchTall,
+    // both ways by the same factor, so the selection keeps its proportions
+    StretchBoth,
+    Straighten,
+    InvertSelection,
+    SwapSelection,
+    LockSelection,
+    UnlockSelection,
+    ToggleWrap,
+    ToggleTextDirection,
+    ExportDot,
+    ExportAscii,
+    ExportComment,
+    ExportSelection,
+    DeleteShape,
+    Complete,
+    UpgradeAscii,
+    // redraws every line glyph from the lines around it
+    NormalizeLines,
+    NextBox,
+    PrevBox,
+    MoveLeft,
+    MoveRight,
+    MoveUp,
+    MoveDown,
+    Confirm,
+    KeepDrawing,
+}
+
+impl Action {
+    // whether replaying the action redraws what it did, saving and the system clipboard don't,
+    // nor do the prompts since what is typed into them isn't recorded
+    fn replayable(self) -> bool {
+        match self {
+            Action::Quit
+            | Action::SaveAndQuit
+            | Action::ForceQuit
+            | Action::Save
+            | Action::SaveAll
+            | Action::ToggleRecording
+            | Action::GroupUndo
+            | Action::Replay
+            | Action::SaveMacro
+            | Action::RunMacro
+            | Action::ColorPicker
+            | Action::NextTab
+            | Action::PrevTab
+            | Action::QuickOpen
+            | Action::SystemCopy
+            | Action::SystemPaste
+            | Action::ExportDot
+            | Action::ExportAscii
+            | Action::ExportComment
+            | Action::ExportSelection
+            | Action::Diagnostics
+            | Action::SetBrush
+            | Action::SetTitle
+            | Action::Table
+            | Action::MapChars
+            | Action::PlaceText => false,
+            #[cfg(feature = "png")]
+            Action::ExportPng => false,
+            _ => true,
         }
+    }
 
-        // progress global state
+    // what the status bar calls an action in its hints, None for ones not worth a hint
+    fn hint(self) -> Option<&'static str> {
+        Some(match self {
+            Action::Confirm => "confirm",
+            Action::Cancel => "cancel",
+            Action::KeepDrawing => "next",
+            Action::BoxMode => "box",
+            Action::ArrowMode => "arrow",
+            Action::SelectMode => "select",
+            Action::EllipseMode => "ellipse",
+            Action::TraceMode => "trace",
+            Action::ExtendLine => "extend",
+            Action::AddWaypoint => "bend",
+            Action::RemoveWaypoint => "unbend",
+            Action::ToggleBoxCenter => "centre",
+            Action::ToggleBoxSquare => "square",
+            Action::ToggleBoxSide(UP) => "top",
+            Action::ToggleBoxSide(DOWN) => "bottom",
+            Action::ToggleBoxSide(LEFT) => "left",
+            Action::ToggleBoxSide(RIGHT) => "right",
+            Action::FitBox => "fit",
+            Action::StretchWide => "stretch wide",
+            Action::StretchTall => "stretch tall",
+            Action::StretchBoth => "stretch both",
+            Action::Straighten => "straighten",
+            Action::InvertSelection => "invert",
+            Action::SwapSelection => "swap",
+            Action::LockSelection => "lock",
+            Action::UnlockSelection => "unlock",
+            Action::MapChars => "map",
+            Action::ExportSelection => "export",
+            Action::Complete => "expand",
+            _ => return None,
+        })
+    }
 
+    // actions that never touch committed cells, so the cached canvas stays valid while dragging
+    fn keeps_canvas(self) -> bool {
+        matches!(
+            self,
+            Action::MoveLeft
+                | Action::MoveRight
+                | Action::MoveUp
+                | Action::MoveDown
+                | Action::AddWaypoint
+                | Action::RemoveWaypoint
+                | Action::ToggleBoxCenter
+                | Action::ToggleBoxSquare
+                | Action::ToggleBoxSide(_)
+                | Action::ToggleDots
+                | Action::ToggleGuideRow
+                | Action::ToggleGuideColumn
+                | Action::ClearGuides
+                | Action::ToggleCursor
+                | Action::PickBrush
+                | Action::ToggleLineNumbers
+                | Action::ToggleFrame
+                | Action::CenterView
+                | Action::GroupUndo
+                | Action::ToggleStats
+                | Action::ToggleCoords
+                | Action::ToggleCrosshair
+                | Action::CycleExportView
+                | Action::FitBox
+                | Action::MoveAnchor(..)
+                | Action::ScaleFactor(_)
+                | Action::NextBox
+                | Action::PrevBox
+                | Action::CycleColor
+                | Action::Copy
+                | Action::ExportSelection
+                | Action::Diagnostics
+                | Action::ShowRow
+        )
     }
-    Ok(())
 }
 
-fn process_input(global_state: &mut GlobalState) -> Result<&mut GlobalState> {
-    if event::poll(Duration::from_millis(250)).context("event poll failed")? {
-        if let Event::Key(key) = event::read().context("event read failed")? {
-            match key.modifiers {
-                crossterm::event::KeyModifiers::CONTROL => match key.code {
-                    KeyCode::Char('b') => {
-                        if global_state.input_state != InputState::BOX {
-                            global_state.input_state = InputState::BOX;
-                            global_state.start_pos = global_state.current_pos;
-                        }
-                    }
-                    KeyCode::Char('a') => {
-                        if global_state.input_state != InputState::ARROW {
-                            global_state.input_state = InputState::ARROW;
-                            global_state.start_pos = global_state.current_pos;
-                        }
-                    }
-                    KeyCode::Char('s') => {
-                        let chars = generate_output(global_state);
-                        let file = File::create("output.txt")?;
-                        let mut buf_write = BufWriter::new(file);
-                        buf_write.write(chars.as_bytes());
-                        buf_write.flush().unwrap();
-                    }
-                    _ => {}
-                },
-                crossterm::event::KeyModifiers::NONE => match key.code {
-                    KeyCode::Esc => global_state.should_quit = true,
-                    KeyCode::Char(char) if global_state.input_state == InputState::INSERT => {
-                            global_state.diagram.insert(global_state.current_pos, char);
-                            if global_state.current_pos.0 < global_state.window_size.0 {
-                                global_state.current_pos.0 += 1;
-                            }
-                    }
-                    KeyCode::Backspace if global_state.input_state == InputState::INSERT => {
-                        if global_state.current_pos.0 > 0 {
-                            global_state.current_pos.0 -= 1;
-                            global_state.diagram.remove(&global_state.current_pos);
-                        }
-                    }
-                    KeyCode::Left => {
-                        if global_state.current_pos.0 > 0 {
-                            global_state.prev_pos = global_state.current_pos;
-                            global_state.current_pos.0 -= 1;
-                        }
-                    }
-                    KeyCode::Right => {
-                        if global_state.current_pos.0 < global_state.window_size.0 {
-                            global_state.prev_pos = global_state.current_pos;
-                            global_state.current_pos.0 += 1;
-                        }
-                    }
-                    KeyCode::Up => {
-                        if global_state.current_pos.1 > 0 {
-                            global_state.prev_pos = global_state.current_pos;
-                            global_state.current_pos.1 -= 1;
-                        }
-                    }
-                    KeyCode::Down => {
-                        if global_state.current_pos.1 < global_state.window_size.1 {
-                            global_state.prev_pos = global_state.current_pos;
-                            global_state.current_pos.1 += 1;
-                        }
-                    }
-                    KeyCode::Enter => {
-                        match global_state.input_state {
-                            InputState::BOX => {
-                                // confirm box 
-                                for pair in global_state.preview.iter() {
-                                    global_state.diagram.insert(*pair.0, *pair.1);
-                                }
-                                global_state.preview.clear();
-                                global_state.input_state = InputState::INSERT;
-                            },
-                            InputState::INSERT => {
-                                // new line 
-                                if global_state.current_pos.1 < global_state.window_size.1 {
-                                    global_state.current_pos.1 += 1;
-                                }
-                                global_state.current_pos.0 = 0;
-                            }
-                            InputState::ARROW => {
-                                // confirm arrow  
-                                for pair in global_state.preview.iter() {
-                                    global_state.diagram.insert(*pair.0, *pair.1);
-                                }
-                                global_state.preview.clear();
-                                global_state.input_state = InputState::INSERT;
-                            },
-                        }
-                    }
-                    _ => {}
-                },
-                _ => {}
+type Keymap = HashMap<(KeyModifiers, KeyCode), Action>;
+
+// unmodified keys that only mean something in one mode, also listed as its status bar hints
+const MODE_KEYS: [(InputState, KeyCode, Action); 26] = [
+    (InputState::ARROW, KeyCode::Char(' '), Action::AddWaypoint),
+    (InputState::ARROW, KeyCode::Backspace, Action::RemoveWaypoint),
+    (InputState::ARROW, KeyCode::Tab, Action::KeepDrawing),
+    (InputState::BOX, KeyCode::Tab, Action::KeepDrawing),
+    (InputState::ELLIPSE, KeyCode::Tab, Action::KeepDrawing),
+    (InputState::BOX, KeyCode::Char('c'), Action::ToggleBoxCenter),
+    (InputState::BOX, KeyCode::Char('s'), Action::ToggleBoxSquare),
+    (InputState::BOX, KeyCode::Char('f'), Action::FitBox),
+    (InputState::BOX, KeyCode::Char('t'), Action::ToggleBoxSide(UP)),
+    (InputState::BOX, KeyCode::Char('b'), Action::ToggleBoxSide(DOWN)),
+    (InputState::BOX, KeyCode::Char('l'), Action::ToggleBoxSide(LEFT)),
+    (InputState::BOX, KeyCode::Char('r'), Action::ToggleBoxSide(RIGHT)),
+    (InputState::ELLIPSE, KeyCode::Char('c'), Action::ToggleBoxCenter),
+    (InputState::ELLIPSE, KeyCode::Char('s'), Action::ToggleBoxSquare),
+    (InputState::SELECT, KeyCode::Char('h'), Action::StretchWide),
+    (InputState::SELECT, KeyCode::Char('v'), Action::StretchTall),
+    (InputState::SELECT, KeyCode::Char('b'), Action::StretchBoth),
+    (InputState::SELECT, KeyCode::Char('s'), Action::Straighten),
+    (InputState::SELECT, KeyCode::Char('i'), Action::InvertSelection),
+    (InputState::SELECT, KeyCode::Char('x'), Action::SwapSelection),
+    (InputState::SELECT, KeyCode::Char('l'), Action::LockSelection),
+    (InputState::SELECT, KeyCode::Char('u'), Action::UnlockSelection),
+    (InputState::SELECT, KeyCode::Char('m'), Action::MapChars),
+    (InputState::SELECT, KeyCode::Char('w'), Action::ExportSelection),
+    (InputState::INSERT, KeyCode::Tab, Action::Complete),
+    (InputState::INSERT, KeyCode::Backspace, Action::Backspace),
+];
+
+// cells Alt and an arrow grow or shrink the canvas by
+const RESIZE_STEP: i32 = 10;
+
+fn default_keymap() -> Keymap {
+    let ctrl = KeyModifiers::CONTROL;
+    let ctrl_shift = KeyModifiers::CONTROL | KeyModifiers::SHIFT;
+    #[allow(unused_mut)]
+    let mut keymap = HashMap::from([
+        ((KeyModifiers::NONE, KeyCode::Esc), Action::Cancel),
+        ((ctrl, KeyCode::Char('q')), Action::Quit),
+        ((ctrl, KeyCode::Char('x')), Action::SaveAndQuit),
+        ((KeyModifiers::ALT, KeyCode::Char('q')), Action::ForceQuit),
+        ((ctrl, KeyCode::Char('s')), Action::Save),
+        ((ctrl, KeyCode::Char('o')), Action::QuickOpen),
+        ((KeyModifiers::ALT, KeyCode::Char('s')), Action::SaveAll),
+        ((ctrl, KeyCode::PageDown), Action::NextTab),
+        ((ctrl, KeyCode::PageUp), Action::PrevTab),
+        ((ctrl, KeyCode::Char('b')), Action::BoxMode),
+        ((ctrl, KeyCode::Char('a')), Action::ArrowMode),
+        ((ctrl, KeyCode::Char('r')), Action::SelectMode),
+        ((ctrl, KeyCode::Char('e')), Action::EllipseMode),
+        ((ctrl, KeyCode::Char('t')), Action::TraceMode),
+        ((ctrl, KeyCode::Char('u')), Action::ExtendLine),
+        ((ctrl, KeyCode::Char('d')), Action::ToggleDots),
+        ((KeyModifiers::ALT, KeyCode::Char('h')), Action::ToggleGuideRow),
+        ((KeyModifiers::ALT, KeyCode::Char('j')), Action::ToggleGuideColumn),
+        ((KeyModifiers::ALT, KeyCode::Char('z')), Action::ClearGuides),
+        ((KeyModifiers::ALT, KeyCode::Char('e')), Action::ToggleCursor),
+        ((ctrl, KeyCode::Char('l')), Action::ToggleLineNumbers),
+        ((ctrl, KeyCode::Char('f')), Action::ToggleFrame),
+        ((ctrl, KeyCode::Char('y')), Action::CenterView),
+        ((KeyModifiers::ALT, KeyCode::Char('i')), Action::ToggleStats),
+        ((KeyModifiers::ALT, KeyCode::Char('.')), Action::ToggleCoords),
+        ((KeyModifiers::ALT, KeyCode::Char(';')), Action::ToggleCrosshair),
+        ((KeyModifiers::ALT, KeyCode::Char('v')), Action::CycleExportView),
+        ((KeyModifiers::NONE, KeyCode::F(12)), Action::Diagnostics),
+        ((KeyModifiers::SHIFT, KeyCode::F(12)), Action::ShowRow),
+        ((ctrl, KeyCode::Char('g')), Action::ExportDot),
+        ((KeyModifiers::ALT, KeyCode::Char('a')), Action::ExportAscii),
+        ((KeyModifiers::ALT, KeyCode::Char('c')), Action::ExportComment),
+        ((KeyModifiers::ALT, KeyCode::Char('u')), Action::UpgradeAscii),
+        ((KeyModifiers::ALT, KeyCode::Char('=')), Action::NormalizeLines),
+        ((KeyModifiers::ALT, KeyCode::Char('x')), Action::DeleteShape),
+        ((KeyModifiers::ALT, KeyCode::Char('w')), Action::ToggleWrap),
+        ((KeyModifiers::ALT, KeyCode::Char('d')), Action::ToggleTextDirection),
+        ((KeyModifiers::ALT, KeyCode::Char('b')), Action::SetBrush),
+        ((KeyModifiers::ALT, KeyCode::Char('t')), Action::Stamp),
+        ((KeyModifiers::ALT, KeyCode::Char('f')), Action::PickBrush),
+        ((KeyModifiers::ALT, KeyCode::Char('l')), Action::SetTitle),
+        ((KeyModifiers::ALT, KeyCode::Char(',')), Action::PlaceText),
+        ((KeyModifiers::ALT, KeyCode::Char('g')), Action::Table),
+        ((KeyModifiers::ALT, KeyCode::Char('r')), Action::ToggleRecording),
+        ((KeyModifiers::ALT, KeyCode::Char('y')), Action::Replay),
+        ((KeyModifiers::ALT, KeyCode::Char('k')), Action::SaveMacro),
+        ((KeyModifiers::ALT, KeyCode::Char('m')), Action::RunMacro),
+        ((ctrl, KeyCode::Char('c')), Action::Copy),
+        ((ctrl, KeyCode::Char('v')), Action::Paste),
+        ((ctrl_shift, KeyCode::Char('c')), Action::SystemCopy),
+        ((ctrl_shift, KeyCode::Char('C')), Action::SystemCopy),
+        ((ctrl_shift, KeyCode::Char('v')), Action::SystemPaste),
+        ((ctrl_shift, KeyCode::Char('V')), Action::SystemPaste),
+        ((ctrl, KeyCode::Char('k')), Action::CycleColor),
+        ((KeyModifiers::ALT, KeyCode::Char('o')), Action::ColorPicker),
+        ((ctrl, KeyCode::Char('z')), Action::Undo),
+        ((ctrl, KeyCode::Char('w')), Action::GroupUndo),
+        ((KeyModifiers::ALT, KeyCode::Char('n')), Action::NextBox),
+        ((KeyModifiers::ALT, KeyCode::Char('p')), Action::PrevBox),
+        ((KeyModifiers::NONE, KeyCode::Left), Action::MoveLeft),
+        ((KeyModifiers::NONE, KeyCode::Right), Action::MoveRight),
+        ((KeyModifiers::NONE, KeyCode::Up), Action::MoveUp),
+        ((KeyModifiers::NONE, KeyCode::Down), Action::MoveDown),
+        ((KeyModifiers::SHIFT, KeyCode::Left), Action::MoveAnchor(-1, 0)),
+        ((KeyModifiers::SHIFT, KeyCode::Right), Action::MoveAnchor(1, 0)),
+        ((KeyModifiers::SHIFT, KeyCode::Up), Action::MoveAnchor(0, -1)),
+        ((KeyModifiers::SHIFT, KeyCode::Down), Action::MoveAnchor(0, 1)),
+        ((ctrl, KeyCode::Char('n')), Action::FitContent),
+        ((KeyModifiers::ALT, KeyCode::Left), Action::ResizeCanvas(-RESIZE_STEP, 0)),
+        ((KeyModifiers::ALT, KeyCode::Right), Action::ResizeCanvas(RESIZE_STEP, 0)),
+        ((KeyModifiers::ALT, KeyCode::Up), Action::ResizeCanvas(0, -RESIZE_STEP)),
+        ((KeyModifiers::ALT, KeyCode::Down), Action::ResizeCanvas(0, RESIZE_STEP)),
+        ((KeyModifiers::NONE, KeyCode::Enter), Action::Confirm),
+    ]);
+    #[cfg(feature = "png")]
+    keymap.insert((ctrl, KeyCode::Char('p')), Action::ExportPng);
+    keymap
+}
+
+// one GlobalState per open file, switched with Ctrl+PageUp/PageDown
+struct Workspace {
+    buffers: Vec<GlobalState>,
+    active: usize,
+    // internal clipboard, shared between tabs
+    clipboard: HashMap<(i32, i32), Cell>,
+    keymap: Keymap,
+    should_quit: bool,
+    // waiting for y/n after quitting with unsaved changes
+    confirm_quit: bool,
+    // an action waiting for y/n because it would write over many glyphs
+    confirm_overwrite: Option<Action>,
+    recording: Option<Recording>,
+    // the last finished recording
+    take: Option<Recording>,
+    // when replay of the take started and the next action to apply
+    replay: Option<(Instant, usize)>,
+    // a line of text being typed in the status bar, it takes every key until Enter or Esc
+    prompt: Option<Prompt>,
+    // when swap files were last written
+    autosaved: Instant,
+    // what the quick-open prompt lists, read when it opens
+    recent: Vec<PathBuf>,
+    // the status bar shows inverted until then after a refused action
+    flash: Option<Instant>,
+    // the color highlighted in the open picker, it takes every key until Enter or Esc
+    color_picker: Option<usize>,
+    // when the blinking cursor last changed and whether it is hidden now
+    blinked: Instant,
+    cursor_hidden: bool,
+    // the slow task running in the background, one at a time
+    task: Option<Task>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum PromptKind {
+    Brush,
+    Title,
+    Table,
+    CharMap,
+    Place,
+    Open,
+    SaveMacro,
+    RunMacro,
+}
+
+struct Prompt {
+    kind: PromptKind,
+    label: &'static str,
+    input: String,
+}
+
+// actions done on one tab, timestamped so replay can keep their pace
+struct Recording {
+    buffer: usize,
+    // the tab as it was when recording started
+    start: GlobalState,
+    started: Instant,
+    actions: Vec<(Instant, Action)>,
+}
+
+impl Workspace {
+    fn active(&mut self) -> &mut GlobalState {
+        &mut self.buffers[self.active]
+    }
+
+    // bound keys first, then the keys whose meaning depends on the mode
+    fn action_for(&self, key: KeyEvent) -> Option<Action> {
+        let input_state = self.buffers[self.active].input_state;
+        // shapes finish on the confirm key, Enter stays a newline in INSERT whatever it is
+        if input_state != InputState::INSERT {
+            let confirm = self.confirm_key();
+            if (key.modifiers, key.code) == confirm {
+                return Some(Action::Confirm);
+            }
+            if key.code == KeyCode::Enter && confirm.1 != KeyCode::Enter {
+                return None;
             }
+        }
+        if let Some(action) = self.keymap.get(&(key.modifiers, key.code)) {
+            return Some(*action);
+        }
+        let hard_space = (KeyModifiers::CONTROL, KeyCode::Char(' '));
+        if input_state == InputState::INSERT && (key.modifiers, key.code) == hard_space {
+            return Some(Action::Insert(HARD_SPACE));
+        }
+        if key.modifiers != KeyModifiers::NONE {
+            return None;
+        }
+        let mode_key = MODE_KEYS
+            .iter()
+            .find(|(mode, code, _)| *mode == input_state && *code == key.code);
+        if let Some((_, _, action)) = mode_key {
+            return Some(*action);
+        }
+        match (input_state, key.code) {
+            (InputState::SELECT, KeyCode::Char(digit @ '2'..='9')) => {
+                Some(Action::ScaleFactor(digit as i32 - '0' as i32))
+            }
+            (InputState::INSERT, KeyCode::Char(char)) => Some(Action::Insert(char)),
+            _ => None,
+        }
+    }
 
+    fn confirm_key(&self) -> (KeyModifiers, KeyCode) {
+        let config = &self.buffers[self.active].config;
+        config.confirm_key.unwrap_or((KeyModifiers::NONE, KeyCode::Enter))
+    }
 
-            if global_state.input_state == InputState::BOX {
-                let lefty = min(global_state.current_pos.0, global_state.start_pos.0); 
-                let righty = max(global_state.current_pos.0, global_state.start_pos.0); 
-                let topx = min(global_state.current_pos.1, global_state.start_pos.1); 
-                let bottomx = max(global_state.current_pos.1, global_state.start_pos.1); 
+    // time until swap files are due, None with autosave off
+    fn autosave_wait(&self) -> Option<Duration> {
+        let seconds = self.buffers[0].config.autosave;
+        let interval = Duration::from_secs(seconds as u64);
+        (seconds > 0).then(|| interval.saturating_sub(self.autosaved.elapsed()))
+    }
 
-                global_state.preview.clear();
+    // time until the flash ends, None without one
+    fn flash_wait(&self) -> Option<Duration> {
+        self.flash.map(|until| until.saturating_duration_since(Instant::now()))
+    }
 
-                for y in lefty..righty {
-                    global_state.preview.insert((y, topx), '─'); 
-                    global_state.preview.insert((y, bottomx), '─'); 
-                }
-                for x in topx..bottomx{
-                    global_state.preview.insert((lefty, x), '│'); 
-                    global_state.preview.insert((righty, x), '│'); 
-                }
-                global_state.preview.insert((lefty, topx), '╭'); 
-                global_state.preview.insert((righty, topx), '╮'); 
-                global_state.preview.insert((lefty, bottomx), '╰'); 
-                global_state.preview.insert((righty, bottomx), '╯'); 
-            } else if global_state.input_state == InputState::ARROW {
-                if global_state.prev_pos.0 + 1 == global_state.current_pos.0 { 
-                    global_state.preview.insert(global_state.current_pos, '▶');
-                } else if global_state.prev_pos.0 - 1 == global_state.current_pos.0 { 
-                    global_state.preview.insert(global_state.current_pos, '◀');
-                } else if global_state.prev_pos.1 + 1 == global_state.current_pos.1 {
-                    global_state.preview.insert(global_state.current_pos, '▼');
-                } else if global_state.prev_pos.1 - 1 == global_state.current_pos.1 {
-                    global_state.preview.insert(global_state.current_pos, '▲');
-                } else {
-                    global_state.preview.insert(global_state.current_pos, '◆');
-                }
-                match global_state.preview.get(&global_state.prev_pos) {
-                    Some('▶') => {
-                        if global_state.prev_pos.0 + 1 == global_state.current_pos.0 { 
-                            global_state.preview.insert(global_state.prev_pos, '─');
-                        } else if global_state.prev_pos.0 - 1 == global_state.current_pos.0 { 
-                            global_state.preview.insert(global_state.prev_pos, '─');
-                        } else if global_state.prev_pos.1 + 1 == global_state.current_pos.1 {
-                            global_state.preview.insert(global_state.prev_pos, '╮');
-                        } else if global_state.prev_pos.1 - 1 == global_state.current_pos.1 {
-                            global_state.preview.insert(global_state.prev_pos, '╯');
-                        } else {
-                            global_state.preview.insert(global_state.prev_pos, '+');
-                        }
-                    }
-                    Some('◀') => {
-                        if global_state.prev_pos.0 + 1 == global_state.current_pos.0 { 
-                            global_state.preview.insert(global_state.prev_pos, '─');
-                        } else if global_state.prev_pos.0 - 1 == global_state.current_pos.0 { 
-                            global_state.preview.insert(global_state.prev_pos, '─');
-                        } else if global_state.prev_pos.1 + 1 == global_state.current_pos.1 {
-                            global_state.preview.insert(global_state.prev_pos, '╭');
-                        } else if global_state.prev_pos.1 - 1 == global_state.current_pos.1 {
-                            global_state.preview.insert(global_state.prev_pos, '╰');
-                        } else {
-                            global_state.preview.insert(global_state.prev_pos, '+');
-                        }
-                    }
-                    Some('▼') => {
-                        if global_state.prev_pos.0 + 1 == global_state.current_pos.0 { 
-                            global_state.preview.insert(global_state.prev_pos,  '╰');
-                        } else if global_state.prev_pos.0 - 1 == global_state.current_pos.0 { 
-                            global_state.preview.insert(global_state.prev_pos,  '╯');
-                        } else if global_state.prev_pos.1 + 1 == global_state.current_pos.1 {
-                            global_state.preview.insert(global_state.prev_pos, '│');
-                        } else if global_state.prev_pos.1 - 1 == global_state.current_pos.1 {
-                            global_state.preview.insert(global_state.prev_pos, '│');
-                        } else {
-                            global_state.preview.insert(global_state.prev_pos, '+');
-                        }
-                    }
-                    Some('▲') => {
-                        if global_state.prev_pos.0 + 1 == global_state.current_pos.0 { 
-                            global_state.preview.insert(global_state.prev_pos, '╭');
-                        } else if global_state.prev_pos.0 - 1 == global_state.current_pos.0 { 
-                            global_state.preview.insert(global_state.prev_pos, '╮');
-                        } else if global_state.prev_pos.1 + 1 == global_state.current_pos.1 {
-                            global_state.preview.insert(global_state.prev_pos, '│');
-                        } else if global_state.prev_pos.1 - 1 == global_state.current_pos.1 {
-                            global_state.preview.insert(global_state.prev_pos, '│');
-                        } else {
-                            global_state.preview.insert(global_state.prev_pos, '+');
-                        }
-                    }
-                    Some(_) => {}
-                    None => {},
-                }
-                // global_state.preview.insert(global_state.prev_pos, '+');
-            } else {
-                global_state.preview.clear();
+    // time until the spinner turns, None with no task running
+    fn task_wait(&self) -> Option<Duration> {
+        self.task.as_ref().map(|_| SPIN_TIME)
+    }
+
+    // runs `work` on a thread, refused while another task is still going
+    fn spawn(&mut self, label: String, work: impl FnOnce() -> Finished + Send + 'static) {
+        if let Some(task) = &self.task {
+            self.active().message = format!("still {}", task.label);
+            self.active().blocked = true;
+            return;
+        }
+        let handle = thread::spawn(work);
+        self.task = Some(Task { label, started: Instant::now(), handle });
+    }
+
+    // takes in what a task that has ended came back with
+    fn finish_task(&mut self) {
+        if !self.task.as_ref().is_some_and(|task| task.handle.is_finished()) {
+            return;
+        }
+        let task = self.task.take().unwrap();
+        match task.handle.join() {
+            #[cfg(feature = "png")]
+            Ok(Finished::Exported(buffer, message)) => self.buffers[buffer].message = message,
+            Ok(Finished::Opened(path, Ok(buffer))) => {
+                self.buffers.push(*buffer);
+                self.active = self.buffers.len() - 1;
+                remember_recent(&path);
             }
-            return Ok(global_state);
+            Ok(Finished::Opened(_, Err(err))) => self.active().message = format!("{err:#}"),
+            Err(_) => self.active().message = format!("{} failed", task.label),
         }
     }
-    Ok(global_state)
+
+    // time until the cursor blinks, None with blink off
+    fn blink_wait(&self) -> Option<Duration> {
+        let blink = self.buffers[0].config.blink;
+        blink.then(|| BLINK_TIME.saturating_sub(self.blinked.elapsed()))
+    }
+
+    // the flash and bell for whatever action was just refused on any tab
+    fn signal_blocked(&mut self) {
+        let mut blocked = false;
+        for buffer in &mut self.buffers {
+            blocked |= std::mem::take(&mut buffer.blocked);
+        }
+        let config = &self.buffers[self.active].config;
+        if blocked && config.bell {
+            ring_bell();
+        }
+        if blocked && !config.no_flash {
+            self.flash = Some(Instant::now() + FLASH_TIME);
+        }
+    }
+
+    // time until the next replayed action is due, None when not replaying
+    fn replay_wait(&self) -> Option<Duration> {
+        let (replay_started, next) = self.replay?;
+        let take = self.take.as_ref()?;
+        let due = take.actions[next].0 - take.started;
+        Some(due.saturating_sub(replay_started.elapsed()))
+    }
+
+    fn next_tab(&mut self) {
+        self.active = (self.active + 1) % self.buffers.len();
+    }
+
+    fn prev_tab(&mut self) {
+        self.active = (self.active + self.buffers.len() - 1) % self.buffers.len();
+    }
+}
+
+fn main() -> Result<()> {
+    let mut config = Config::load()?;
+    let mut paths = Vec::new();
+    let mut export = None;
+    let mut check = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--export" => {
+                let files = (args.next(), args.next(), args.next());
+                let (Some(format), Some(input), Some(output)) = files else {
+                    anyhow::bail!("--export needs a format (or auto), an input and an output file");
+                };
+                export = Some((format, PathBuf::from(input), PathBuf::from(output)));
+            }
+            "--check" => check = true,
+            "--single-width" => config.single_width = true,
+            "--wrap" => config.wrap = true,
+            "--scroll" => config.scroll = true,
+            "--width" => config.width = Some(size_arg(&arg, args.next())?),
+            "--height" => config.height = Some(size_arg(&arg, args.next())?),
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+    // a canvas bigger than the window is only reachable by scrolling to it
+    config.scroll |= config.width.is_some() || config.height.is_some();
+    config.monochrome = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty())
+        || env::var("TERM").is_ok_and(|term| term == "dumb")
+        || color_count() < 8;
+    if config.monochrome {
+        style::force_color_output(false);
+    }
+    if let Some((format, input, output)) = export {
+        return export_headless(&config, &format, &input, &output);
+    }
+    if check {
+        return check_files(&config, &paths);
+    }
+    let mut buffers = Vec::new();
+    for path in paths {
+        buffers.push(GlobalState::from_file(&path, &config)?);
+        remember_recent(&path);
+    }
+    if buffers.is_empty() {
+        buffers.push(GlobalState::new(&config));
+    }
+    let mut workspace = Workspace {
+        buffers,
+        active: 0,
+        clipboard: HashMap::new(),
+        keymap: default_keymap(),
+        should_quit: false,
+        confirm_quit: false,
+        confirm_overwrite: None,
+        recording: None,
+        take: None,
+        replay: None,
+        prompt: None,
+        autosaved: Instant::now(),
+        flash: None,
+        recent: Vec::new(),
+        color_picker: None,
+        blinked: Instant::now(),
+        cursor_hidden: false,
+        task: None,
+    };
+    if let Some(key) = workspace.buffers[0].config.quit_key {
+        workspace.keymap.retain(|_, action| *action != Action::Quit);
+        workspace.keymap.insert(key, Action::Quit);
+    }
+
+    // a panic would otherwise leave the shell in raw mode on the alternate screen
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if TERMINAL_ACTIVE.swap(false, Ordering::Relaxed) {
+            let _ = reset_terminal(&mut io::stdout());
+        }
+        default_hook(info);
+    }));
+
+    let mut terminal = setup_terminal().context("setup failed")?;
+    run(&mut terminal, &mut workspace).context("app loop failed")?;
+    restore_terminal(&mut terminal).context("restore terminal failed")?;
+    // remember where each saved file was left, whether or not it changed this session
+    for buffer in workspace.buffers.iter().filter(|buffer| buffer.path.exists()) {
+        if let Err(err) = write_sidecar(buffer) {
+            eprintln!("{err:#}");
+        }
+    }
+    // leaving on purpose, unsaved changes included, is not a crash to recover from
+    for buffer in &workspace.buffers {
+        remove_swap(buffer);
+    }
+    // a line per tab so wrappers and scripts can tell whether the last save landed
+    for buffer in &workspace.buffers {
+        let state = match (buffer.dirty, buffer.saved, buffer.path.exists()) {
+            (true, _, _) => "unsaved changes",
+            (false, true, _) => "saved",
+            (false, false, true) => "unchanged",
+            (false, false, false) => "nothing to save",
+        };
+        eprintln!("{}: {state}", buffer.path.display());
+    }
+    Ok(())
+}
+
+// --export: opens `input` as the editor would and writes it to `output` without the terminal,
+// for scripts and builds, `auto` picks the format from the output's extension
+fn export_headless(config: &Config, format: &str, input: &Path, output: &Path) -> Result<()> {
+    if !input.exists() {
+        anyhow::bail!("{} does not exist", input.display());
+    }
+    let global_state = GlobalState::from_file(input, config)?;
+    let margin = config.margin;
+    let format = match format {
+        "auto" => match output.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) => extension,
+            None => anyhow::bail!("cannot tell the format of {}, name it", output.display()),
+        },
+        format => format,
+    };
+    let bytes = match format {
+        "txt" => saved_text(&global_state).into_bytes(),
+        "ascii" => ascii_output(&global_state).into_bytes(),
+        "comment" => {
+            export_comment(&global_state.diagram, marker(&global_state), margin).into_bytes()
+        }
+        "dot" => export_dot(&global_state.diagram)?.into_bytes(),
+        "tdb" => save_bin(&global_state.diagram),
+        #[cfg(feature = "png")]
+        "png" => term_draw::export_png(&global_state.diagram, margin)?,
+        _ => anyhow::bail!(
+            "unknown export format {format}, expected txt, ascii, comment, dot, tdb{}",
+            if cfg!(feature = "png") { " or png" } else { " (png needs the png feature)" }
+        ),
+    };
+    fs::write(output, bytes).with_context(|| format!("cannot write {}", output.display()))
+}
+
+// --check: loads each file as the editor would and reports anything it had to change or could
+// not place, for linting committed diagrams, a summary per file and an error if any had problems
+fn check_files(config: &Config, paths: &[PathBuf]) -> Result<()> {
+    if paths.is_empty() {
+        anyhow::bail!("--check needs the files to check");
+    }
+    let mut failed = 0;
+    for path in paths {
+        match check_file(config, path) {
+            Ok(summary) => println!("{}: {summary}", path.display()),
+            Err(err) => {
+                eprintln!("{err:#}");
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!("{failed} of {} files have problems", paths.len());
+    }
+    Ok(())
+}
+
+fn check_file(config: &Config, path: &Path) -> Result<String> {
+    if !path.exists() {
+        anyhow::bail!("{} does not exist", path.display());
+    }
+    let global_state = GlobalState::from_file(path, config)?;
+    let diagram = &global_state.diagram;
+    let mut problems = Vec::new();
+    let garbled = diagram.iter().filter(|(_, cell)| cell.char == '\u{FFFD}').count();
+    if garbled > 0 {
+        problems.push(format!("{garbled} control characters or bad glyphs (shown as \u{FFFD})"));
+    }
+    if !is_binary(path) {
+        let contents = fs::read_to_string(path)?;
+        let contents = contents.strip_prefix('\u{FEFF}').unwrap_or(&contents);
+        let dropped = contents.chars().filter(|char| char.width() == Some(0)).count();
+        if dropped > 0 {
+            problems.push(format!("{dropped} zero width characters that take no cell"));
+        }
+    }
+    if let Some((left, top, right, bottom)) = diagram.bounds() {
+        if left < 0 || top < 0 {
+            problems.push("cells above or left of the canvas".to_string());
+        }
+        if let Some(width) = config.width.filter(|width| right >= *width) {
+            problems.push(format!("content reaches column {}, past --width {width}", right + 1));
+        }
+        if let Some(height) = config.height.filter(|height| bottom >= *height) {
+            problems.push(format!("content reaches row {}, past --height {height}", bottom + 1));
+        }
+    }
+    if !problems.is_empty() {
+        anyhow::bail!("{}: {}", path.display(), problems.join(", "));
+    }
+    let (width, height) = diagram.size();
+    Ok(format!("ok, {width}x{height}, {} glyphs", diagram.chars().count()))
+}
+
+// the cells after --width or --height, at least one and no more than max_canvas
+fn size_arg(flag: &str, value: Option<String>) -> Result<i32> {
+    let value = value.with_context(|| format!("{flag} needs a number of cells"))?;
+    match value.parse::<i32>() {
+        Ok(cells) if cells > 0 => Ok(cells),
+        _ => anyhow::bail!("{flag} expects a positive number of cells, got {value}"),
+    }
+}
+
+// set once the terminal accepted the keyboard enhancement flags, so restore pops them
+static KEYBOARD_ENHANCED: AtomicBool = AtomicBool::new(false);
+// raw mode and the alternate screen are on, so the panic hook knows to undo them
+static TERMINAL_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+fn setup_terminal() -> Result<Terminal<CrosstermBackend<Stdout>>> {
+    let mut stdout = io::stdout();
+    enable_raw_mode().context("failed to enable raw mode")?;
+    TERMINAL_ACTIVE.store(true, Ordering::Relaxed);
+    execute!(stdout, EnterAlternateScreen).context("unable to enter alternate screen")?;
+    // pasted text arrives in one piece instead of as keys that would switch modes
+    execute!(stdout, EnableBracketedPaste).context("unable to enable bracketed paste")?;
+    // lets Ctrl+Shift combinations through on terminals that support it
+    if supports_keyboard_enhancement().unwrap_or(false) {
+        execute!(
+            stdout,
+            PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES)
+        )
+        .context("unable to enable keyboard enhancement")?;
+        KEYBOARD_ENHANCED.store(true, Ordering::Relaxed);
+    }
+    Terminal::new(CrosstermBackend::new(stdout)).context("creating terminal failed")
+}
+
+fn restore_terminal(terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> Result<()> {
+    TERMINAL_ACTIVE.store(false, Ordering::Relaxed);
+    reset_terminal(terminal.backend_mut())
+}
+
+// also run from the panic hook, where only stdout is left to write to
+fn reset_terminal(out: &mut impl Write) -> Result<()> {
+    if KEYBOARD_ENHANCED.swap(false, Ordering::Relaxed) {
+        execute!(out, PopKeyboardEnhancementFlags)
+            .context("unable to disable keyboard enhancement")?;
+    }
+    execute!(out, SetCursorStyle::DefaultUserShape).context("unable to reset cursor style")?;
+    execute!(out, DisableBracketedPaste).context("unable to disable bracketed paste")?;
+    disable_raw_mode().context("failed to disable raw mode")?;
+    execute!(out, LeaveAlternateScreen).context("unable to switch to main screen")?;
+    execute!(out, Show).context("unable to show cursor")
+}
+
+// what a save does with the end of the file, by default every row ends in a newline
+fn end_output(config: &Config, mut chars: String) -> String {
+    if config.trim_blank_lines {
+        let end = chars.trim_end_matches('\n').len();
+        chars.truncate(end);
+        if end > 0 {
+            chars.push('\n');
+        }
+    }
+    if config.no_final_newline && chars.ends_with('\n') {
+        chars.pop();
+    }
+    if config.crlf {
+        chars = chars.replace('\n', "\r\n");
+    }
+    chars
+}
+
+fn render_status(workspace: &Workspace) -> Line<'static> {
+    if let Some(prompt) = &workspace.prompt {
+        return Line::from(format!("{} {}", prompt.label, prompt.input));
+    }
+    let mut spans = Vec::new();
+    for (i, buffer) in workspace.buffers.iter().enumerate() {
+        let tab = Span::raw(format!(" {}:{} ", i + 1, buffer.title()));
+        if i == workspace.active {
+            spans.push(tab.reversed());
+        } else {
+            spans.push(tab);
+        }
+    }
+    let global_state = &workspace.buffers[workspace.active];
+    // canvas coordinates, the gutter is not counted
+    let (x, y) = global_state.current_pos;
+    spans.push(Span::raw(format!(" {},{}", x + 1, y + 1)));
+    // a fixed canvas size, from --width and --height or Alt and an arrow
+    if global_state.config.width.is_some() || global_state.config.height.is_some() {
+        let (width, height) = global_state.canvas_size();
+        spans.push(Span::raw(format!(" of {width}x{height}")));
+    }
+    if global_state.at_edge() > 0 {
+        spans.push(Span::raw(" at edge").reversed());
+    }
+    if global_state.group.is_some() {
+        spans.push(Span::raw(" grouping"));
+    }
+    // what is under the cursor, to tell look-alike glyphs apart
+    if let Some(cell) = global_state.diagram.get(&global_state.current_pos) {
+        let mut inspector = format!(" {:?} U+{:04X}", cell.char, cell.char as u32);
+        if let Some(fg) = cell.fg {
+            inspector.push_str(&format!(" {}", format!("{fg:?}").to_lowercase()));
+        }
+        spans.push(Span::raw(inspector));
+    }
+    if let Some(task) = &workspace.task {
+        let frame = task.started.elapsed().as_millis() / SPIN_TIME.as_millis();
+        let spinner = SPINNER[frame as usize % SPINNER.len()];
+        spans.push(Span::raw(format!(" {spinner} {}", task.label)));
+    }
+    let message = &global_state.message;
+    if !message.is_empty() {
+        spans.push(Span::raw(format!(" {message}")));
+    } else {
+        let confirm = workspace.confirm_key();
+        let hints = mode_hints(&workspace.keymap, confirm, global_state.input_state);
+        spans.push(Span::raw(format!("  {hints}")).add_modifier(Modifier::DIM));
+    }
+    Line::from(spans)
+}
+
+// the keys of the current mode as `key action` pairs, looked up in the bindings so they stay
+// right when those change
+fn mode_hints(
+    keymap: &Keymap,
+    confirm: (KeyModifiers, KeyCode),
+    input_state: InputState,
+) -> String {
+    let bound = |action: Action| {
+        keymap
+            .iter()
+            .filter(|(_, bound)| **bound == action)
+            .map(|(key, _)| key_name(*key))
+            .min()
+    };
+    let mut hints = Vec::new();
+    let global = match input_state {
+        InputState::INSERT => [
+            Action::BoxMode,
+            Action::ArrowMode,
+            Action::SelectMode,
+            Action::EllipseMode,
+            Action::TraceMode,
+        ]
+        .as_slice(),
+        _ => {
+            hints.push(format!("{} confirm", key_name(confirm)));
+            [Action::Cancel].as_slice()
+        }
+    };
+    for &action in global {
+        if let (Some(key), Some(hint)) = (bound(action), action.hint()) {
+            hints.push(format!("{key} {hint}"));
+        }
+    }
+    for (_, code, action) in MODE_KEYS.iter().filter(|(mode, _, _)| *mode == input_state) {
+        if let Some(hint) = action.hint() {
+            hints.push(format!("{} {hint}", key_name((KeyModifiers::NONE, *code))));
+        }
+    }
+    if input_state == InputState::SELECT {
+        hints.push("2-9 factor".to_string());
+    }
+    hints.join(" · ")
+}
+
+fn key_name((modifiers, code): (KeyModifiers, KeyCode)) -> String {
+    let mut name = String::new();
+    for (modifier, prefix) in [
+        (KeyModifiers::CONTROL, "Ctrl-"),
+        (KeyModifiers::ALT, "Alt-"),
+        (KeyModifiers::SHIFT, "Shift-"),
+    ] {
+        if modifiers.contains(modifier) {
+            name.push_str(prefix);
+        }
+    }
+    match code {
+        KeyCode::Char(' ') => name.push_str("Space"),
+        KeyCode::Char(char) if modifiers.is_empty() => name.push(char),
+        KeyCode::Char(char) => name.push(char.to_ascii_uppercase()),
+        code => name.push_str(&format!("{code:?}")),
+    }
+    name
+}
+
+// where a canvas position lands in the terminal, None when it is off screen
+fn screen_pos(area: Rect, viewport: (i32, i32), (x, y): (i32, i32)) -> Option<(u16, u16)> {
+    let (x, y) = (x - viewport.0, y - viewport.1);
+    let inside = (0..area.width as i32).contains(&x) && (0..area.height as i32).contains(&y);
+    inside.then(|| (area.x + x as u16, area.y + y as u16))
+}
+
+// faint dot on every empty cell, screen only so it never ends up in a save
+fn render_dots(buffer: &mut Buffer, area: Rect, global_state: &GlobalState) {
+    let (left, top) = global_state.viewport;
+    for y in top..top + area.height as i32 {
+        let mut x = left;
+        while x < left + area.width as i32 {
+            match global_state.cell_at((x, y)) {
+                Some(char) => x += global_state.advance(char),
+                None => {
+                    if let Some((x, y)) = screen_pos(area, global_state.viewport, (x, y)) {
+                        buffer.get_mut(x, y).set_char('·').set_fg(Color::DarkGray);
+                    }
+                    x += 1;
+                }
+            }
+        }
+    }
+}
+
+// faint lines along the guide rows and columns where nothing is drawn, so they never cover a
+// glyph or end up in a save
+fn render_guides(buffer: &mut Buffer, area: Rect, global_state: &GlobalState) {
+    if global_state.guide_rows.is_empty() && global_state.guide_columns.is_empty() {
+        return;
+    }
+    let (left, top) = global_state.viewport;
+    for y in top..top + area.height as i32 {
+        let mut x = left;
+        while x < left + area.width as i32 {
+            if let Some(char) = global_state.cell_at((x, y)) {
+                x += global_state.advance(char);
+                continue;
+            }
+            let row = global_state.guide_rows.contains(&y);
+            let column = global_state.guide_columns.contains(&x);
+            let guide = match (row, column) {
+                (true, true) => Some('┼'),
+                (true, false) => Some('┄'),
+                (false, true) => Some('┆'),
+                (false, false) => None,
+            };
+            if let (Some(guide), Some((x, y))) = (guide, screen_pos(area, (left, top), (x, y))) {
+                buffer.get_mut(x, y).set_char(guide).set_fg(Color::DarkGray);
+            }
+            x += 1;
+        }
+    }
+}
+
+// the shape being drawn, on top of the cached committed text
+fn render_preview(buffer: &mut Buffer, area: Rect, global_state: &GlobalState) {
+    for (pos, char) in &global_state.preview {
+        if let Some((x, y)) = screen_pos(area, global_state.viewport, *pos) {
+            buffer.get_mut(x, y).set_char(*char);
+        }
+    }
+}
+
+fn render_colors(buffer: &mut Buffer, area: Rect, global_state: &GlobalState) {
+    for (pos, cell) in &global_state.diagram {
+        if let Some(fg) = cell.fg {
+            let covered = global_state.preview.contains_key(pos);
+            let screen = screen_pos(area, global_state.viewport, *pos);
+            if let Some((x, y)) = screen.filter(|_| !covered) {
+                buffer.get_mut(x, y).set_fg(fg);
+            }
+        }
+    }
+    if let Some(fg) = global_state.active_color {
+        for pos in global_state.preview.keys() {
+            if let Some((x, y)) = screen_pos(area, global_state.viewport, *pos) {
+                buffer.get_mut(x, y).set_fg(fg);
+            }
+        }
+    }
+}
+
+// marks the corner being dragged so it stands out from the rest of a busy canvas
+fn render_handle(buffer: &mut Buffer, area: Rect, global_state: &GlobalState) {
+    if !matches!(global_state.input_state, InputState::BOX | InputState::ELLIPSE) {
+        return;
+    }
+    if let Some((x, y)) = screen_pos(area, global_state.viewport, global_state.current_pos) {
+        let style = if global_state.config.monochrome {
+            Style::new().add_modifier(Modifier::REVERSED)
+        } else {
+            Style::new().fg(Color::Black).bg(Color::Cyan)
+        };
+        buffer.get_mut(x, y).set_style(style);
+    }
+}
+
+fn render_cursors(buffer: &mut Buffer, area: Rect, global_state: &GlobalState) {
+    if global_state.input_state != InputState::INSERT {
+        return;
+    }
+    for pos in &global_state.cursors {
+        if let Some((x, y)) = screen_pos(area, global_state.viewport, *pos) {
+            let style = if global_state.config.monochrome {
+                Style::new().add_modifier(Modifier::REVERSED)
+            } else {
+                Style::new().fg(Color::Black).bg(Color::Cyan)
+            };
+            buffer.get_mut(x, y).set_style(style);
+        }
+    }
+}
+
+// a faint background along the cursor's row and column as far as the canvas goes, underlined
+// without color
+fn render_crosshair(buffer: &mut Buffer, area: Rect, global_state: &GlobalState) {
+    let (width, height) = global_state.canvas_size();
+    let (left, top) = global_state.viewport;
+    let (col, row) = global_state.current_pos;
+    let style = if global_state.config.monochrome {
+        Style::new().add_modifier(Modifier::UNDERLINED)
+    } else {
+        Style::new().bg(Color::Indexed(236))
+    };
+    let row_cells = (left..left + area.width as i32).map(|x| (x, row));
+    let column_cells = (top..top + area.height as i32).map(|y| (col, y));
+    for (x, y) in row_cells.chain(column_cells) {
+        if x >= width || y >= height {
+            continue;
+        }
+        if let Some((x, y)) = screen_pos(area, (left, top), (x, y)) {
+            buffer.get_mut(x, y).set_style(style);
+        }
+    }
+}
+
+// `col,row` just above and right of the cursor, or wherever beside it still fits on screen
+fn render_coords(buffer: &mut Buffer, area: Rect, global_state: &GlobalState) {
+    let Some((x, y)) = screen_pos(area, global_state.viewport, global_state.current_pos) else {
+        return;
+    };
+    let (col, row) = global_state.current_pos;
+    let label = format!(" {},{} ", col + 1, row + 1);
+    let width = label.len() as u16;
+    let x = if x + 2 + width <= area.right() { x + 2 } else { x.saturating_sub(width + 1) };
+    let y = if y > area.y { y - 1 } else { y + 1 };
+    if x < area.x || y >= area.bottom() {
+        return;
+    }
+    let style = if global_state.config.monochrome {
+        Style::new().add_modifier(Modifier::REVERSED)
+    } else {
+        Style::new().fg(Color::Black).bg(Color::Gray)
+    };
+    buffer.set_string(x, y, label, style);
+}
+
+fn render_selection(buffer: &mut Buffer, area: Rect, global_state: &GlobalState) {
+    if global_state.input_state != InputState::SELECT {
+        return;
+    }
+    let (left, top, right, bottom) = global_state.selection();
+    for y in top..=bottom {
+        for x in left..=right {
+            if let Some((x, y)) = screen_pos(area, global_state.viewport, (x, y)) {
+                buffer.get_mut(x, y).modifier.insert(Modifier::REVERSED);
+            }
+        }
+    }
+    // the part marked to swap is underlined
+    let Some((left, top, right, bottom)) = global_state.swap_mark else {
+        return;
+    };
+    for y in top..=bottom {
+        for x in left..=right {
+            if let Some((x, y)) = screen_pos(area, global_state.viewport, (x, y)) {
+                buffer.get_mut(x, y).modifier.insert(Modifier::UNDERLINED);
+            }
+        }
+    }
+}
+
+// colors the terminal can show, from terminfo through tput, else crossterm's guess from TERM
+fn color_count() -> i32 {
+    let tput = Command::new("tput").arg("colors").stderr(Stdio::null()).output().ok();
+    tput.filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok()?.trim().parse().ok())
+        .unwrap_or_else(|| style::available_color_count() as i32)
+}
+
+// the border of the box the cursor sits on is traced in bold yellow, bold reversed without color
+fn render_border(buffer: &mut Buffer, area: Rect, global_state: &GlobalState) {
+    let on_line = global_state
+        .diagram
+        .get(&global_state.current_pos)
+        .is_some_and(|cell| glyph_sides(cell.char).is_some());
+    if !on_line {
+        return;
+    }
+    let Some((left, top, right, bottom)) = box_on_border(global_state) else {
+        return;
+    };
+    let edge = (left..=right)
+        .flat_map(|x| [(x, top), (x, bottom)])
+        .chain((top + 1..bottom).flat_map(|y| [(left, y), (right, y)]));
+    let style = if global_state.config.monochrome {
+        Style::new().add_modifier(Modifier::BOLD | Modifier::REVERSED)
+    } else {
+        Style::new().fg(Color::Yellow).add_modifier(Modifier::BOLD)
+    };
+    for pos in edge {
+        if let Some((x, y)) = screen_pos(area, global_state.viewport, pos) {
+            buffer.get_mut(x, y).set_style(style);
+        }
+    }
+}
+
+// locked regions are drawn dim
+fn render_locked(buffer: &mut Buffer, area: Rect, global_state: &GlobalState) {
+    for &(left, top, right, bottom) in &global_state.locked {
+        for y in top..=bottom {
+            for x in left..=right {
+                if let Some((x, y)) = screen_pos(area, global_state.viewport, (x, y)) {
+                    buffer.get_mut(x, y).modifier.insert(Modifier::DIM);
+                }
+            }
+        }
+    }
+}
+
+// counted afresh each frame the panel is open, the size is what a save would write
+fn render_stats(frame: &mut Frame, area: Rect, global_state: &GlobalState) {
+    let (width, height) = global_state
+        .bounds()
+        .map_or((0, 0), |(left, top, right, bottom)| (right - left + 1, bottom - top + 1));
+    let heads = global_state
+        .diagram
+        .values()
+        .filter(|cell| head_side(cell.char).is_some())
+        .count();
+    let size = saved_size(global_state);
+    let output = global_state.diagram.text((0, 0), size, global_state.config.fill);
+    let lines = vec![
+        Line::from(format!("cells   {}", global_state.diagram.len())),
+        Line::from(format!("size    {width}x{height}")),
+        Line::from(format!("boxes   {}", global_state.diagram.boxes().len())),
+        Line::from(format!("arrows  {heads}")),
+        Line::from(format!("output  {} bytes", trim_trailing_spaces(&output).len())),
+    ];
+    let panel = Rect {
+        x: area.right().saturating_sub(24).max(area.x),
+        y: area.y,
+        width: area.width.min(24),
+        height: area.height.min(lines.len() as u16 + 2),
+    };
+    frame.render_widget(Clear, panel);
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title(" stats ")),
+        panel,
+    );
+}
+
+// the recent files next to the open prompt, below it when the status bar is at the top, the one
+// in the prompt highlighted
+fn render_recent(frame: &mut Frame, area: Rect, recent: &[PathBuf], input: &str, top: bool) {
+    if recent.is_empty() {
+        return;
+    }
+    let lines: Vec<Line> = recent
+        .iter()
+        .map(|path| path.display().to_string())
+        .map(|path| if path == input { Line::from(path).reversed() } else { Line::from(path) })
+        .collect();
+    let height = area.height.min(lines.len() as u16 + 2);
+    let y = if top { area.y } else { area.bottom() - height };
+    let panel = Rect { y, height, ..area };
+    frame.render_widget(Clear, panel);
+    frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(" recent ")), panel);
+}
+
+// a swatch per PICKER color in the middle of the canvas, the highlighted one in brackets and
+// named in the title
+fn render_color_picker(frame: &mut Frame, area: Rect, selected: usize) {
+    let lines: Vec<Line> = PICKER
+        .chunks(PICKER_ROW)
+        .enumerate()
+        .map(|(row, colors)| {
+            let spans = colors.iter().enumerate().flat_map(|(column, color)| {
+                let (open, close) =
+                    if row * PICKER_ROW + column == selected { ("[", "]") } else { (" ", " ") };
+                let swatch = Style::new().fg(color.unwrap_or(Color::Reset));
+                // the default color has no swatch of its own
+                let glyph = if color.is_some() { "██" } else { "··" };
+                [Span::raw(open), Span::styled(glyph, swatch), Span::raw(close)]
+            });
+            Line::from(spans.collect::<Vec<_>>())
+        })
+        .collect();
+    let name = match PICKER[selected] {
+        Some(color) => format!(" {color} "),
+        None => " default ".to_string(),
+    };
+    let (width, height) = (PICKER_ROW as u16 * 4 + 2, lines.len() as u16 + 2);
+    let panel = Rect {
+        x: area.x + area.width.saturating_sub(width) / 2,
+        y: area.y + area.height.saturating_sub(height) / 2,
+        width: width.min(area.width),
+        height: height.min(area.height),
+    };
+    frame.render_widget(Clear, panel);
+    frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(name)), panel);
+}
+
+// the text an export would write, from the same functions the export keys use
+fn render_export_view(frame: &mut Frame, area: Rect, global_state: &GlobalState, view: ExportView) {
+    let margin = global_state.config.margin;
+    let (title, text) = match view {
+        ExportView::Ascii => (" ascii ", ascii_output(global_state)),
+        ExportView::Cropped => (" cropped ", global_state.diagram.crop_margin(margin)),
+        ExportView::Comment => {
+            (" comment ", export_comment(&global_state.diagram, marker(global_state), margin))
+        }
+    };
+    frame.render_widget(Paragraph::new(text).block(Block::bordered().title(title)), area);
+}
+
+// 1-based row numbers, right aligned with a space before the canvas
+fn render_line_numbers(frame: &mut Frame, area: Rect, first_row: i32) {
+    let width = area.width.saturating_sub(1) as usize;
+    let numbers: Vec<Line> = (first_row + 1..=first_row + area.height as i32)
+        .map(|row| Line::from(format!("{row:>width$} ")))
+        .collect();
+    frame.render_widget(Paragraph::new(numbers).fg(Color::DarkGray), area);
+}
+
+// replaces a `:name:` ending just before the cursor with its text from the config, the
+// cursor ends up after the first row of it
+fn expand_mnemonic(global_state: &mut GlobalState) {
+    let (x, y) = global_state.current_pos;
+    let char_at = |x: i32| global_state.diagram.get(&(x, y)).map(|cell| cell.char);
+    if char_at(x - 1) != Some(':') {
+        global_state.message = "no :mnemonic: before the cursor".to_string();
+        return;
+    }
+    let mut start = x - 2;
+    let mut name = Vec::new();
+    while let Some(char) = char_at(start).filter(|char| mnemonic_char(*char)) {
+        name.push(char);
+        start -= 1;
+    }
+    if name.is_empty() || char_at(start) != Some(':') {
+        global_state.message = "no :mnemonic: before the cursor".to_string();
+        return;
+    }
+    let name: String = name.into_iter().rev().collect();
+    let Some(text) = global_state.config.mnemonics.get(&name).cloned() else {
+        global_state.message = format!("unknown mnemonic :{name}:");
+        return;
+    };
+    for x in start..x {
+        global_state.diagram.remove(&(x, y));
+    }
+    global_state.current_pos = (start, y);
+    paste_text(global_state, &text);
+    let first_row = text.lines().next().unwrap_or_default();
+    let width: i32 = first_row.chars().map(|char| global_state.advance(char)).sum();
+    global_state.current_pos.0 = start + width;
+    global_state.dirty = true;
+}
+
+// places each line of text one row further down, starting at the cursor column, its spaces are
+// transparent so a pasted block lays over the drawing instead of blanking it
+fn paste_text(global_state: &mut GlobalState, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+    let color = global_state.active_color;
+    global_state.diagram.write_text(global_state.current_pos, text, color);
+    global_state.dirty = true;
+}
+
+// cells of the whole diagram, keyed relative to its top-left corner
+fn copy_cells(global_state: &GlobalState) -> HashMap<(i32, i32), Cell> {
+    let Some((left, top, _, _)) = global_state.bounds() else {
+        return HashMap::new();
+    };
+    global_state
+        .diagram
+        .iter()
+        .map(|(pos, cell)| ((pos.0 - left, pos.1 - top), *cell))
+        .collect()
+}
+
+fn paste_cells(global_state: &mut GlobalState, cells: &HashMap<(i32, i32), Cell>) {
+    if cells.is_empty() {
+        return;
+    }
+    let (x, y) = global_state.current_pos;
+    let mut cells: Vec<_> = cells.iter().collect();
+    cells.sort_by_key(|(offset, _)| (offset.1, offset.0));
+    for (offset, cell) in cells {
+        let pos = (x.saturating_add(offset.0), y.saturating_add(offset.1));
+        global_state.diagram.insert(pos, *cell);
+    }
+    global_state.dirty = true;
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(TABLE[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+// OSC52 asks the terminal itself to set the clipboard, which also works over ssh
+fn copy_to_system_clipboard(text: &str) -> Result<()> {
+    let mut stdout = io::stdout();
+    write!(stdout, "\x1b]52;c;{}\x07", base64(text.as_bytes()))?;
+    stdout.flush()?;
+    Ok(())
+}
+
+// terminals rarely answer OSC52 reads, so ask whichever clipboard tool is installed
+fn read_system_clipboard() -> Result<String> {
+    let tools: [&[&str]; 5] = [
+        &["wl-paste", "--no-newline"],
+        &["xclip", "-selection", "clipboard", "-o"],
+        &["xsel", "--clipboard", "--output"],
+        &["pbpaste"],
+        &["powershell", "-NoProfile", "-Command", "Get-Clipboard"],
+    ];
+    for tool in tools {
+        let output = Command::new(tool[0])
+            .args(&tool[1..])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output();
+        if let Ok(output) = output {
+            if output.status.success() {
+                return Ok(String::from_utf8_lossy(&output.stdout).to_string());
+            }
+        }
+    }
+    anyhow::bail!("no clipboard tool found (wl-paste, xclip, xsel, pbpaste)")
+}
+
+fn draw(
+    terminal: &mut Terminal<CrosstermBackend<Stdout>>,
+    workspace: &mut Workspace,
+    cursor_mode: &mut Option<InputState>,
+) -> Result<()> {
+    let terminal_size = terminal.size().context("cannot get terminal size")?;
+    // some ptys report 0x0 for a moment, the next resize event draws again
+    if terminal_size.width == 0 || terminal_size.height < 2 {
+        return Ok(());
+    }
+    let status_top = workspace.active().config.status_top;
+    let [main_area, status_area] = if status_top {
+        let [status_area, main_area] =
+            Layout::vertical([Constraint::Length(1), Constraint::Min(1)]).areas(terminal_size);
+        [main_area, status_area]
+    } else {
+        Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).areas(terminal_size)
+    };
+    // the cursor sits at the end of the prompt while one is open
+    let prompt_cursor = workspace.prompt.as_ref().map(|prompt| {
+        let width: usize = prompt.input.chars().map(|char| char.width().unwrap_or(0)).sum();
+        (prompt.label.len() + 1 + width) as u16
+    });
+    let global_state = workspace.active();
+    let gutter_width = if global_state.line_numbers {
+        (global_state.viewport.1 + main_area.height as i32).to_string().len() as u16 + 1
+    } else {
+        0
+    };
+    let pane_width = if global_state.export_view.is_some() { main_area.width * 2 / 5 } else { 0 };
+    let [drawing_area, pane_area] =
+        Layout::horizontal([Constraint::Min(1), Constraint::Length(pane_width)]).areas(main_area);
+    let border = global_state.framed.then(Block::bordered);
+    let inner = border.as_ref().map_or(drawing_area, |border| border.inner(drawing_area));
+    let [gutter_area, canvas_area] =
+        Layout::horizontal([Constraint::Length(gutter_width), Constraint::Min(1)]).areas(inner);
+    let window_size = (canvas_area.width as i32, canvas_area.height as i32);
+    if global_state.window_size != window_size {
+        global_state.window_size = window_size;
+        global_state.canvas_stale = true;
+    }
+    // glyphs past the window, after a resize or a paste or stretch that grew the drawing, could
+    // not be reached or seen but are still saved
+    if global_state.canvas_stale && !global_state.config.scroll {
+        let (width, height) = window_size;
+        let outside = global_state
+            .bounds()
+            .is_some_and(|(_, _, right, bottom)| right >= width || bottom >= height);
+        if outside {
+            global_state.config.scroll = true;
+            global_state.message = "drawing is larger than the window, scrolling on".to_string();
+        }
+    }
+    global_state.follow_cursor();
+    if global_state.canvas_stale {
+        global_state.canvas =
+            global_state.diagram.text(global_state.viewport, global_state.window_size, None);
+        global_state.canvas_stale = false;
+    }
+    // after the resize above, which can leave a message
+    let status = render_status(workspace);
+    let global_state = &workspace.buffers[workspace.active];
+    terminal.draw(|frame| {
+        let mut style = Style::default();
+        if global_state.color && !global_state.config.monochrome {
+            style = style.fg(Color::Yellow);
+        }
+
+        let text = Paragraph::new(global_state.canvas.as_str()).style(style);
+        if let Some(border) = border {
+            frame.render_widget(border, drawing_area);
+        }
+        match prompt_cursor {
+            Some(x) => frame.set_cursor(status_area.x + x, status_area.y),
+            // the cursor may sit one past the last column, so it is not clipped to the canvas
+            None => {
+                let x = global_state.current_pos.0 - global_state.viewport.0;
+                let y = global_state.current_pos.1 - global_state.viewport.1;
+                frame.set_cursor(
+                    canvas_area.x.saturating_add(x.max(0) as u16),
+                    canvas_area.y.saturating_add(y.max(0) as u16),
+                )
+            }
+        }
+        frame.render_widget(text, canvas_area);
+        render_preview(frame.buffer_mut(), canvas_area, global_state);
+        if global_state.line_numbers {
+            render_line_numbers(frame, gutter_area, global_state.viewport.1);
+        }
+        render_colors(frame.buffer_mut(), canvas_area, global_state);
+        if global_state.show_dots {
+            render_dots(frame.buffer_mut(), canvas_area, global_state);
+        }
+        render_guides(frame.buffer_mut(), canvas_area, global_state);
+        if global_state.crosshair {
+            render_crosshair(frame.buffer_mut(), canvas_area, global_state);
+        }
+        render_border(frame.buffer_mut(), canvas_area, global_state);
+        render_handle(frame.buffer_mut(), canvas_area, global_state);
+        render_cursors(frame.buffer_mut(), canvas_area, global_state);
+        render_selection(frame.buffer_mut(), canvas_area, global_state);
+        render_locked(frame.buffer_mut(), canvas_area, global_state);
+        if global_state.show_coords {
+            render_coords(frame.buffer_mut(), canvas_area, global_state);
+        }
+        if global_state.show_stats {
+            render_stats(frame, canvas_area, global_state);
+        }
+        if let Some(view) = global_state.export_view {
+            render_export_view(frame, pane_area, global_state, view);
+        }
+        if let Some(prompt) = workspace.prompt.as_ref().filter(|p| p.kind == PromptKind::Open) {
+            render_recent(frame, main_area, &workspace.recent, &prompt.input, status_top);
+        }
+        if let Some(selected) = workspace.color_picker {
+            render_color_picker(frame, canvas_area, selected);
+        }
+        let mut status_bar = Paragraph::new(status);
+        if workspace.flash.is_some() {
+            status_bar = status_bar.reversed();
+        }
+        frame.render_widget(status_bar, status_area);
+    })?;
+    if workspace.cursor_hidden {
+        terminal.hide_cursor()?;
+    }
+    // some terminals hide the cursor in the alternate screen unless its shape is set
+    let input_state = global_state.input_state;
+    if *cursor_mode != Some(input_state) {
+        let cursor_style = match input_state {
+            // the terminal's own blinking would fight the editor's
+            InputState::INSERT if global_state.config.blink => SetCursorStyle::SteadyBar,
+            InputState::INSERT => SetCursorStyle::BlinkingBar,
+            InputState::BOX
+            | InputState::ARROW
+            | InputState::SELECT
+            | InputState::ELLIPSE
+            | InputState::TRACE => SetCursorStyle::SteadyBlock,
+        };
+        execute!(terminal.backend_mut(), cursor_style).context("unable to set cursor style")?;
+        *cursor_mode = Some(input_state);
+    }
+    Ok(())
+}
+
+// blocks until something happens and only redraws when it could have changed the screen
+fn run(
+    terminal: &mut Terminal<CrosstermBackend<Stdout>>,
+    workspace: &mut Workspace,
+) -> Result<()> {
+    let mut cursor_mode = None;
+    draw(terminal, workspace, &mut cursor_mode)?;
+    loop {
+        // while replaying, autosaving or flashing, wait for input only until the next one is due
+        let wait = workspace.replay_wait().into_iter().chain(workspace.autosave_wait());
+        let wait = wait.chain(workspace.flash_wait()).chain(workspace.blink_wait());
+        let wait = wait.chain(workspace.task_wait()).min();
+        if let Some(wait) = wait {
+            if !event::poll(wait).context("event poll failed")? {
+                if workspace.flash.is_some_and(|until| until <= Instant::now()) {
+                    workspace.flash = None;
+                }
+                if workspace.blink_wait().is_some_and(|wait| wait.is_zero()) {
+                    workspace.cursor_hidden = !workspace.cursor_hidden;
+                    workspace.blinked = Instant::now();
+                }
+                replay_due(workspace)?;
+                workspace.finish_task();
+                workspace.signal_blocked();
+                autosave_due(workspace);
+                draw(terminal, workspace, &mut cursor_mode)?;
+                continue;
+            }
+        }
+        let redraw = match event::read().context("event read failed")? {
+            // a held key repeats like presses do, releases are only reported by some terminals
+            // and would type everything twice
+            Event::Key(key) if key.kind == KeyEventKind::Release => false,
+            Event::Key(key) => {
+                process_key(workspace, key)?;
+                true
+            }
+            Event::Paste(text) => {
+                paste_event(workspace, &text);
+                true
+            }
+            Event::Resize(_, _) => true,
+            _ => false,
+        };
+        // the cursor stays in sight while keys come in
+        workspace.cursor_hidden = false;
+        workspace.blinked = Instant::now();
+        workspace.finish_task();
+        workspace.signal_blocked();
+        if workspace.should_quit {
+            break;
+        }
+        if redraw {
+            draw(terminal, workspace, &mut cursor_mode)?;
+        }
+    }
+    // an export still being written is let finish
+    if let Some(task) = workspace.task.take() {
+        let _ = task.handle.join();
+    }
+    Ok(())
+}
+
+fn build_arrow_preview(global_state: &mut GlobalState) {
+    let mut points = vec![global_state.start_pos];
+    points.extend(global_state.waypoints.iter().copied());
+    points.push(global_state.current_pos);
+    global_state.preview = arrow_glyphs(&points, &global_state.glyphs);
+    // an arrow chained from a committed one starts next to it and leaves its head alone, unless
+    // it loops back to end there
+    let start = global_state.start_pos;
+    let chained = start != global_state.current_pos && global_state.diagram.contains_key(&start);
+    if global_state.preview.len() > 1 && chained {
+        global_state.preview.remove(&start);
+    }
+}
+
+// the ellipse filling box_bounds
+fn build_ellipse_preview(global_state: &mut GlobalState) {
+    global_state.preview = ellipse_glyphs(box_bounds(global_state), &global_state.glyphs);
+}
+
+// the diagram saved as it would be with every line glyph and arrow head swapped for ascii
+fn export_ascii(global_state: &GlobalState, path: &Path) -> Result<()> {
+    fs::write(path, ascii_output(global_state))
+        .with_context(|| format!("cannot write {}", path.display()))
+}
+
+fn ascii_output(global_state: &GlobalState) -> String {
+    let plain = global_state.diagram.to_ascii();
+    trim_trailing_spaces(&plain.text((0, 0), saved_size(global_state), global_state.config.fill))
+}
+
+// what starts each line of a comment export
+fn marker(global_state: &GlobalState) -> &str {
+    global_state.config.comment_marker.as_deref().unwrap_or("//")
+}
+
+// at least a window's worth and never less than the content, a fill character backs the
+// drawing up to its bottom-right corner instead
+fn saved_size(global_state: &GlobalState) -> (i32, i32) {
+    let window = global_state.page_size();
+    match (global_state.config.fill, global_state.bounds()) {
+        (_, None) => window,
+        (Some(_), Some((_, _, right, bottom))) => (right + 1, bottom + 1),
+        (None, Some((_, _, right, bottom))) => {
+            (max(window.0, right + 1), max(window.1, bottom + 1))
+        }
+    }
+}
+
+// the file a save writes, in text
+fn saved_text(global_state: &GlobalState) -> String {
+    let size = saved_size(global_state);
+    let mut output = global_state.diagram.text((0, 0), size, global_state.config.fill);
+    if !global_state.tabs.is_empty() {
+        output = restore_tabs(global_state, &output);
+    }
+    end_output(&global_state.config, trim_trailing_spaces(&output))
+}
+
+// what F12 writes for a bug report, the drawing only with diagnostics_contents
+fn diagnostics(global_state: &GlobalState) -> String {
+    let terminal = terminal::size().map_or("unknown".to_string(), |(w, h)| format!("{w}x{h}"));
+    let (width, height) = global_state.canvas_size();
+    let (x, y) = global_state.current_pos;
+    let (left, top) = global_state.viewport;
+    let var = |name| env::var(name).unwrap_or_default();
+    let mut dump = format!(
+        "term-draw {}\n\
+         os {} {}\n\
+         terminal {terminal} TERM={} COLORTERM={}\n\
+         canvas {width}x{height}\n\
+         cells {}\n\
+         mode {}\n\
+         cursor {x},{y}\n\
+         viewport {left},{top}\n\
+         undo steps {}\n\
+         unsaved {}\n\
+         {:#?}\n",
+        env!("CARGO_PKG_VERSION"),
+        env::consts::OS,
+        env::consts::ARCH,
+        var("TERM"),
+        var("COLORTERM"),
+        global_state.diagram.len(),
+        global_state.input_state.name(),
+        global_state.undo.len(),
+        global_state.dirty,
+        global_state.config,
+    );
+    if global_state.config.diagnostics_contents {
+        dump.push('\n');
+        dump.push_str(&saved_text(global_state));
+    }
+    dump
+}
+
+// swaps the run of empty cells each remembered tab stood for back to the tab, a tab whose columns
+// now hold anything stays spaces
+fn restore_tabs(global_state: &GlobalState, text: &str) -> String {
+    let tab = global_state.config.tab_width();
+    let mut restored = String::with_capacity(text.len());
+    for (y, line) in text.lines().enumerate() {
+        let y = y as i32;
+        let mut chars = line.chars();
+        let mut x = 0;
+        while let Some(char) = chars.next() {
+            let stop = (x / tab + 1) * tab;
+            let empty = (x..stop).all(|column| !global_state.diagram.contains_key(&(column, y)));
+            if global_state.tabs.contains(&(x, y)) && empty {
+                restored.push('\t');
+                // the tab's own char is this one, the rest of its span follows
+                for _ in x + 1..stop {
+                    chars.next();
+                }
+                x = stop;
+                continue;
+            }
+            restored.push(char);
+            x += global_state.advance(char);
+        }
+        restored.push('\n');
+    }
+    restored
+}
+
+// a first line like `# term-draw: width=120 height=40 cursor=3,5` gives the canvas size as
+// --width and --height would and the cursor, keys it doesn't know are skipped
+const HEADER: &str = "# term-draw:";
+
+fn read_header(global_state: &mut GlobalState, header: &str) {
+    let number = |value: &str| value.parse::<i32>().ok().filter(|number| *number > 0);
+    for (key, value) in header.split_whitespace().filter_map(|pair| pair.split_once('=')) {
+        match key {
+            "width" => global_state.config.width = number(value).or(global_state.config.width),
+            "height" => global_state.config.height = number(value).or(global_state.config.height),
+            "cursor" => {
+                let cursor = value.split_once(',').and_then(|(x, y)| {
+                    Some((x.parse::<i32>().ok()?.max(0), y.parse::<i32>().ok()?.max(0)))
+                });
+                global_state.current_pos = cursor.unwrap_or(global_state.current_pos);
+            }
+            _ => {}
+        }
+    }
+    global_state.config.scroll |=
+        global_state.config.width.is_some() || global_state.config.height.is_some();
+}
+
+// the header line a save with write_header starts with, the size only when it is fixed
+fn header(global_state: &GlobalState) -> String {
+    let mut header = HEADER.to_string();
+    if let Some(width) = global_state.config.width {
+        header.push_str(&format!(" width={width}"));
+    }
+    if let Some(height) = global_state.config.height {
+        header.push_str(&format!(" height={height}"));
+    }
+    let (x, y) = global_state.current_pos;
+    header.push_str(&format!(" cursor={x},{y}"));
+    header.push_str(if global_state.config.crlf { "\r\n" } else { "\n" });
+    header
+}
+
+// errors read "cannot write to PATH: reason" so the status bar can show them as they are
+fn save_buffer(global_state: &mut GlobalState) -> Result<()> {
+    let path = &global_state.path;
+    if path.is_dir() {
+        anyhow::bail!("cannot write to {}: it is a directory", path.display());
+    }
+    let bytes = if is_binary(path) {
+        save_bin(&global_state.diagram)
+    } else {
+        let mut text = String::new();
+        if global_state.config.bom {
+            text.push('\u{FEFF}');
+        }
+        if global_state.config.write_header {
+            text.push_str(&header(global_state));
+        }
+        text.push_str(&saved_text(global_state));
+        text.into_bytes()
+    };
+    let written = File::create(path).and_then(|file| {
+        let mut buf_write = BufWriter::new(file);
+        buf_write.write_all(&bytes)?;
+        buf_write.flush()
+    });
+    written.with_context(|| format!("cannot write to {}", path.display()))?;
+    global_state.dirty = false;
+    global_state.saved = true;
+    remove_swap(global_state);
+    remember_recent(&global_state.path);
+    write_sidecar(global_state)
+}
+
+// `.tdb` files hold the cells in the compact binary format, colors included
+fn is_binary(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "tdb")
+}
+
+// a saved file doesn't need its swap any more
+fn remove_swap(global_state: &GlobalState) {
+    let _ = fs::remove_file(swap_path(global_state));
+}
+
+// writes a swap file for every tab with unsaved changes once the interval has passed
+fn autosave_due(workspace: &mut Workspace) {
+    if workspace.autosave_wait().is_none_or(|wait| !wait.is_zero()) {
+        return;
+    }
+    workspace.autosaved = Instant::now();
+    for global_state in workspace.buffers.iter_mut().filter(|buffer| buffer.dirty) {
+        if let Err(err) = write_swap(global_state) {
+            global_state.message = format!("autosave failed: {err:#}");
+        }
+    }
+}
+
+// `.name.swap.txt` next to the file, or the absolute path with `/` as `%` in the cache dir
+fn swap_path(global_state: &GlobalState) -> PathBuf {
+    let extension = if global_state.config.swap_json { "swap.json" } else { "swap.txt" };
+    let path = &global_state.path;
+    let cache = global_state.config.swap_in_cache.then(|| {
+        match env::var_os("XDG_CACHE_HOME") {
+            Some(dir) => Some(PathBuf::from(dir)),
+            None => Some(PathBuf::from(env::var_os("HOME")?).join(".cache")),
+        }
+    });
+    match cache.flatten() {
+        Some(dir) => {
+            let absolute = env::current_dir().map_or(path.clone(), |dir| dir.join(path));
+            let name = absolute.to_string_lossy().replace(std::path::MAIN_SEPARATOR, "%");
+            dir.join("term-draw").join(format!("{name}.{extension}"))
+        }
+        None => {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            path.with_file_name(format!(".{name}.{extension}"))
+        }
+    }
+}
+
+fn write_swap(global_state: &GlobalState) -> Result<()> {
+    let path = swap_path(global_state);
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).with_context(|| format!("cannot create {}", dir.display()))?;
+    }
+    let contents = if global_state.config.swap_json {
+        swap_json(global_state)
+    } else {
+        let size = saved_size(global_state);
+        trim_trailing_spaces(&global_state.diagram.text((0, 0), size, None))
+    };
+    fs::write(&path, contents).with_context(|| format!("cannot write {}", path.display()))
+}
+
+// the path, cursor and every cell with its color, in reading order
+fn swap_json(global_state: &GlobalState) -> String {
+    let mut cells: Vec<_> = global_state.diagram.iter().collect();
+    cells.sort_by_key(|((x, y), _)| (*y, *x));
+    let cells: Vec<String> = cells
+        .into_iter()
+        .map(|((x, y), cell)| {
+            let char = json_string(&cell.char.to_string());
+            let color = cell.fg.map_or(String::new(), |fg| {
+                format!(", \"color\": \"{}\"", format!("{fg:?}").to_lowercase())
+            });
+            format!("    {{\"x\": {x}, \"y\": {y}, \"char\": {char}{color}}}")
+        })
+        .collect();
+    let (x, y) = global_state.current_pos;
+    format!(
+        "{{\n  \"path\": {},\n  \"cursor\": [{x}, {y}],\n  \"cells\": [\n{}\n  ]\n}}\n",
+        json_string(&global_state.path.to_string_lossy()),
+        cells.join(",\n")
+    )
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for char in text.chars() {
+        match char {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            char if char.is_control() => quoted.push_str(&format!("\\u{:04x}", char as u32)),
+            char => quoted.push(char),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// `.name.term-draw` next to the file, where the cursor, view and mode are kept between sessions
+fn sidecar_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}.term-draw"))
+}
+
+fn write_sidecar(global_state: &GlobalState) -> Result<()> {
+    let (x, y) = global_state.current_pos;
+    let (view_x, view_y) = global_state.viewport;
+    let sidecar = format!(
+        "cursor = {x},{y}\nviewport = {view_x},{view_y}\nmode = {}\nbox_from_center = {}\n",
+        global_state.input_state.name(),
+        global_state.box_from_center,
+    );
+    let path = sidecar_path(&global_state.path);
+    fs::write(&path, sidecar).with_context(|| format!("cannot write {}", path.display()))
+}
+
+// best effort, a missing or garbled sidecar just means starting at the top-left
+fn read_sidecar(global_state: &mut GlobalState) {
+    let Ok(contents) = fs::read_to_string(sidecar_path(&global_state.path)) else {
+        return;
+    };
+    let pair = |value: &str| {
+        let (x, y) = value.split_once(',')?;
+        Some((x.trim().parse::<i32>().ok()?.max(0), y.trim().parse::<i32>().ok()?.max(0)))
+    };
+    let mut mode = InputState::INSERT;
+    for line in contents.lines() {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let value = value.trim();
+        match key.trim() {
+            "cursor" => {
+                if let Some(pos) = pair(value) {
+                    global_state.current_pos = pos;
+                }
+            }
+            "viewport" => {
+                if let Some(pos) = pair(value) {
+                    global_state.viewport = pos;
+                }
+            }
+            "mode" => {
+                if let Some(found) = InputState::ALL.into_iter().find(|m| m.name() == value) {
+                    mode = found;
+                }
+            }
+            "box_from_center" => global_state.box_from_center = value == "true",
+            _ => {}
+        }
+    }
+    global_state.enter_mode(mode);
+}
+
+fn process_key(workspace: &mut Workspace, key: KeyEvent) -> Result<()> {
+    if workspace.confirm_quit {
+        workspace.confirm_quit = false;
+        workspace.active().message.clear();
+        if key.code == KeyCode::Char('y') {
+            workspace.should_quit = true;
+        }
+        return Ok(());
+    }
+    if let Some(action) = workspace.confirm_overwrite.take() {
+        workspace.active().message.clear();
+        if key.code == KeyCode::Char('y') {
+            apply_confirmed(workspace, action)?;
+        }
+        return Ok(());
+    }
+    if workspace.prompt.is_some() {
+        return prompt_key(workspace, key);
+    }
+    if let Some(selected) = workspace.color_picker {
+        picker_key(workspace, key, selected);
+        return Ok(());
+    }
+    // any key stops a replay
+    if workspace.replay.take().is_some() {
+        workspace.active().message = "replay stopped".to_string();
+        return Ok(());
+    }
+    if let Some(mut action) = workspace.action_for(key) {
+        // Esc with nothing to drop quits, which is never recorded
+        let global_state = workspace.active();
+        let insert = global_state.input_state == InputState::INSERT;
+        if action == Action::Cancel && insert && global_state.cursors.is_empty() {
+            action = Action::Quit;
+        }
+        if let Some(recording) = &mut workspace.recording {
+            if recording.buffer == workspace.active && action.replayable() {
+                recording.actions.push((Instant::now(), action));
+            }
+        }
+        apply_action(workspace, action)?;
+    }
+    Ok(())
+}
+
+// arrows move through the swatches, Enter takes the highlighted color
+fn picker_key(workspace: &mut Workspace, key: KeyEvent, selected: usize) {
+    let last = PICKER.len() - 1;
+    workspace.color_picker = match key.code {
+        KeyCode::Left => Some(selected.saturating_sub(1)),
+        KeyCode::Right => Some(min(selected + 1, last)),
+        KeyCode::Up => Some(selected.checked_sub(PICKER_ROW).unwrap_or(selected)),
+        KeyCode::Down => Some(min(selected + PICKER_ROW, last)),
+        KeyCode::Enter => {
+            let global_state = workspace.active();
+            global_state.active_color = PICKER[selected];
+            global_state.message = match global_state.active_color {
+                Some(color) => format!("color: {color}"),
+                None => "color: default".to_string(),
+            };
+            None
+        }
+        KeyCode::Esc => None,
+        _ => Some(selected),
+    };
+}
+
+fn prompt_key(workspace: &mut Workspace, key: KeyEvent) -> Result<()> {
+    let Some(prompt) = &mut workspace.prompt else {
+        return Ok(());
+    };
+    let typed = !key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT);
+    match key.code {
+        KeyCode::Char(char) if typed => prompt.input.push(char),
+        KeyCode::Backspace => {
+            prompt.input.pop();
+        }
+        KeyCode::Esc => workspace.prompt = None,
+        // Up and Down step through the recent files in the open prompt
+        KeyCode::Up | KeyCode::Down if prompt.kind == PromptKind::Open => {
+            let recent = &workspace.recent;
+            let current = recent.iter().position(|path| path.display().to_string() == prompt.input);
+            let next = match (key.code, current) {
+                (KeyCode::Up, None) => Some(0),
+                (KeyCode::Up, Some(i)) => Some((i + 1).min(recent.len().saturating_sub(1))),
+                (_, Some(i)) if i > 0 => Some(i - 1),
+                _ => None,
+            };
+            prompt.input = next
+                .and_then(|i| recent.get(i))
+                .map_or(String::new(), |path| path.display().to_string());
+        }
+        KeyCode::Enter => {
+            let Some(prompt) = workspace.prompt.take() else {
+                return Ok(());
+            };
+            if prompt.kind == PromptKind::Open {
+                open_path(workspace, Path::new(prompt.input.trim()));
+                return Ok(());
+            }
+            if prompt.kind == PromptKind::SaveMacro {
+                let name = prompt.input.trim();
+                let actions = workspace.take.as_ref().map(|take| take.actions.clone());
+                let actions: Vec<Action> =
+                    actions.unwrap_or_default().into_iter().map(|(_, action)| action).collect();
+                workspace.active().message = if name.is_empty() || name.contains(['[', ']']) {
+                    "a macro needs a name without brackets".to_string()
+                } else {
+                    match write_macro(name, &actions) {
+                        Ok(()) => format!("saved macro {name}, {} actions", actions.len()),
+                        Err(err) => format!("{err:#}"),
+                    }
+                };
+                return Ok(());
+            }
+            if prompt.kind == PromptKind::RunMacro {
+                return run_macro(workspace, prompt.input.trim());
+            }
+            let global_state = workspace.active();
+            match prompt.kind {
+                PromptKind::Brush => {
+                    global_state.message = if prompt.input.is_empty() {
+                        "brush cleared".to_string()
+                    } else {
+                        format!("brush set to {}", prompt.input)
+                    };
+                    global_state.brush = prompt.input;
+                    global_state.active_char = None;
+                }
+                PromptKind::Title => {
+                    let before = global_state.clone();
+                    let step = global_state.undo_step();
+                    set_title(global_state, &prompt.input);
+                    reject_locked_edit(global_state, before);
+                    global_state.push_undo(step);
+                    global_state.canvas_stale = true;
+                }
+                PromptKind::CharMap => match parse_char_map(&prompt.input) {
+                    Ok(map) => {
+                        let before = global_state.clone();
+                        let step = global_state.undo_step();
+                        map_chars(global_state, &map);
+                        reject_locked_edit(global_state, before);
+                        global_state.push_undo(step);
+                        global_state.canvas_stale = true;
+                    }
+                    Err(err) => global_state.message = format!("{err:#}"),
+                },
+                PromptKind::Place => match parse_place(&prompt.input, global_state.canvas_size()) {
+                    Ok((pos, text)) => {
+                        let before = global_state.clone();
+                        let step = global_state.undo_step();
+                        place_text(global_state, pos, text);
+                        global_state.dirty = true;
+                        reject_locked_edit(global_state, before);
+                        global_state.push_undo(step);
+                        global_state.canvas_stale = true;
+                    }
+                    Err(err) => global_state.message = format!("{err:#}"),
+                },
+                PromptKind::Open | PromptKind::SaveMacro | PromptKind::RunMacro => {}
+                PromptKind::Table => {
+                    let before = global_state.clone();
+                    let step = global_state.undo_step();
+                    let table = parse_table(&prompt.input);
+                    match table.and_then(|table| draw_table(global_state, table)) {
+                        Ok(()) => {
+                            reject_locked_edit(global_state, before);
+                            global_state.push_undo(step);
+                            global_state.canvas_stale = true;
+                        }
+                        Err(err) => global_state.message = format!("{err:#}"),
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// switches to the tab already showing `path` or opens it in a new one
+fn open_path(workspace: &mut Workspace, path: &Path) {
+    if path.as_os_str().is_empty() {
+        workspace.active().message = "no file to open".to_string();
+        return;
+    }
+    let wanted = fs::canonicalize(path).ok();
+    let same = |buffer: &GlobalState| {
+        buffer.path == path || wanted.is_some() && fs::canonicalize(&buffer.path).ok() == wanted
+    };
+    if let Some(i) = workspace.buffers.iter().position(same) {
+        workspace.active = i;
+        remember_recent(path);
+        return;
+    }
+    let config = workspace.active().config.clone();
+    let path = path.to_path_buf();
+    let label = format!("loading {}", path.display());
+    workspace.spawn(label, move || {
+        let buffer = GlobalState::from_file(&path, &config).map(Box::new);
+        Finished::Opened(path, buffer)
+    });
+}
+
+// text pasted into the terminal goes in as it is, into the prompt when one is open
+fn paste_event(workspace: &mut Workspace, text: &str) {
+    if let Some(prompt) = &mut workspace.prompt {
+        prompt.input.push_str(text.lines().next().unwrap_or_default());
+        return;
+    }
+    let global_state = workspace.active();
+    let before = (!global_state.locked.is_empty()).then(|| global_state.clone());
+    let step = global_state.undo_step();
+    paste_text(global_state, text);
+    if let Some(before) = before {
+        reject_locked_edit(global_state, before);
+    }
+    global_state.push_undo(step);
+    global_state.canvas_stale = true;
+}
+
+// applies every recorded action whose time has come
+fn replay_due(workspace: &mut Workspace) -> Result<()> {
+    while let (Some(wait), Some((started, next))) = (workspace.replay_wait(), workspace.replay) {
+        if !wait.is_zero() {
+            return Ok(());
+        }
+        let Some(take) = &workspace.take else {
+            return Ok(());
+        };
+        let action = take.actions[next].1;
+        let done = next + 1 == take.actions.len();
+        workspace.active = take.buffer;
+        workspace.replay = (!done).then_some((started, next + 1));
+        apply_action(workspace, action)?;
+        if done {
+            workspace.active().message = "replay done".to_string();
+        }
+    }
+    Ok(())
+}
+
+// brings the recorded tab back to where the take started and plays it from there
+fn start_replay(workspace: &mut Workspace) {
+    if let Some(recording) = workspace.recording.take() {
+        workspace.take = Some(recording);
+    }
+    let Some(take) = &workspace.take else {
+        workspace.active().message = "nothing recorded".to_string();
+        return;
+    };
+    workspace.active = take.buffer;
+    let window_size = workspace.buffers[take.buffer].window_size;
+    workspace.buffers[take.buffer] = take.start.clone();
+    workspace.buffers[take.buffer].window_size = window_size;
+    workspace.buffers[take.buffer].canvas_stale = true;
+    if take.actions.is_empty() {
+        workspace.active().message = "nothing recorded".to_string();
+    } else {
+        workspace.replay = Some((Instant::now(), 0));
+        workspace.active().message = "replaying, any key stops".to_string();
+    }
+}
+
+// plays a saved macro from the cursor at once, undone as a single step
+fn run_macro(workspace: &mut Workspace, name: &str) -> Result<()> {
+    let macros = read_macros();
+    let Some((_, actions)) = macros.iter().find(|(known, _)| known == name) else {
+        let names: Vec<&str> = macros.iter().map(|(name, _)| name.as_str()).collect();
+        workspace.active().message = if names.is_empty() {
+            "no macros saved, Alt-K saves the recording as one".to_string()
+        } else {
+            format!("no macro {name}, saved are {}", names.join(", "))
+        };
+        return Ok(());
+    };
+    let global_state = workspace.active();
+    let step = global_state.undo_step();
+    let undo = std::mem::take(&mut global_state.undo);
+    let mut skipped = 0;
+    for &action in actions {
+        // leaving other tabs, prompts and quitting are not part of the drawing
+        if action.replayable() {
+            apply_confirmed(workspace, action)?;
+        } else {
+            skipped += 1;
+        }
+    }
+    let global_state = workspace.active();
+    global_state.undo = undo;
+    global_state.push_undo(step);
+    if skipped > 0 {
+        global_state.message = format!("ran macro {name}, skipped {skipped} it can't replay");
+    } else if global_state.message.is_empty() {
+        global_state.message = format!("ran macro {name}");
+    }
+    Ok(())
+}
+
+fn apply_action(workspace: &mut Workspace, action: Action) -> Result<()> {
+    match action {
+        Action::NextTab => {
+            workspace.next_tab();
+            return Ok(());
+        }
+        Action::PrevTab => {
+            workspace.prev_tab();
+            return Ok(());
+        }
+        Action::Quit => {
+            if workspace.buffers.iter().any(|buffer| buffer.dirty) {
+                workspace.confirm_quit = true;
+                workspace.active().message = "unsaved changes, quit anyway? (y/n)".to_string();
+            } else {
+                workspace.should_quit = true;
+            }
+            return Ok(());
+        }
+        Action::ForceQuit => {
+            workspace.should_quit = true;
+            return Ok(());
+        }
+        #[cfg(feature = "png")]
+        Action::ExportPng => {
+            let (buffer, global_state) = (workspace.active, workspace.active());
+            let path = global_state.path.with_extension("png");
+            let (diagram, margin) = (global_state.diagram.clone(), global_state.config.margin);
+            workspace.spawn("exporting png".to_string(), move || {
+                let png = term_draw::export_png(&diagram, margin).and_then(|png| {
+                    fs::write(&path, png)
+                        .with_context(|| format!("cannot write {}", path.display()))
+                });
+                Finished::Exported(buffer, match png {
+                    Ok(()) => format!("exported {}", path.display()),
+                    Err(err) => format!("png export failed: {err:#}"),
+                })
+            });
+            return Ok(());
+        }
+        Action::ToggleRecording => {
+            let message = match workspace.recording.take() {
+                Some(recording) => {
+                    let message = format!("recorded {} actions", recording.actions.len());
+                    workspace.take = Some(recording);
+                    message
+                }
+                None => {
+                    workspace.recording = Some(Recording {
+                        buffer: workspace.active,
+                        start: workspace.active().clone(),
+                        started: Instant::now(),
+                        actions: Vec::new(),
+                    });
+                    "recording".to_string()
+                }
+            };
+            workspace.active().message = message;
+            return Ok(());
+        }
+        Action::Replay => {
+            start_replay(workspace);
+            return Ok(());
+        }
+        Action::SaveMacro => {
+            if let Some(recording) = workspace.recording.take() {
+                workspace.take = Some(recording);
+            }
+            if workspace.take.as_ref().is_none_or(|take| take.actions.is_empty()) {
+                workspace.active().message = "nothing recorded, Alt-R records a macro".to_string();
+                return Ok(());
+            }
+            let (kind, label) = (PromptKind::SaveMacro, "save recording as macro:");
+            workspace.prompt = Some(Prompt { kind, label, input: String::new() });
+            return Ok(());
+        }
+        Action::ColorPicker => {
+            let active = workspace.active().active_color;
+            let selected = PICKER.iter().position(|color| *color == active).unwrap_or(0);
+            workspace.color_picker = Some(selected);
+            return Ok(());
+        }
+        Action::RunMacro => {
+            let (kind, label) = (PromptKind::RunMacro, "run macro:");
+            workspace.prompt = Some(Prompt { kind, label, input: String::new() });
+            return Ok(());
+        }
+        Action::SetTitle => {
+            let prompt = Prompt { kind: PromptKind::Title, label: "title:", input: String::new() };
+            workspace.prompt = Some(prompt);
+            return Ok(());
+        }
+        Action::PlaceText => {
+            let (kind, label) = (PromptKind::Place, "place (column,row text):");
+            workspace.prompt = Some(Prompt { kind, label, input: String::new() });
+            return Ok(());
+        }
+        Action::MapChars => {
+            let label = "map (pairs like *● o○):";
+            let input = workspace.active().config.char_map.clone();
+            workspace.prompt = Some(Prompt { kind: PromptKind::CharMap, label, input });
+            return Ok(());
+        }
+        Action::Table => {
+            let label = "table (rows x columns, cells width x height):";
+            let input = "2x3 8x1".to_string();
+            workspace.prompt = Some(Prompt { kind: PromptKind::Table, label, input });
+            return Ok(());
+        }
+        Action::QuickOpen => {
+            workspace.recent = read_recent();
+            let input = String::new();
+            workspace.prompt = Some(Prompt { kind: PromptKind::Open, label: "open:", input });
+            return Ok(());
+        }
+        Action::SetBrush => {
+            let input = workspace.active().brush.clone();
+            workspace.prompt = Some(Prompt { kind: PromptKind::Brush, label: "brush:", input });
+            return Ok(());
+        }
+        Action::SaveAll => {
+            let mut report = Vec::new();
+            for buffer in workspace.buffers.iter_mut().filter(|buffer| buffer.dirty) {
+                let name = buffer.path.display().to_string();
+                report.push(match save_buffer(buffer) {
+                    Ok(()) => format!("saved {name}"),
+                    Err(err) => format!("{err:#}"),
+                });
+            }
+            workspace.active().message = if report.is_empty() {
+                "nothing to save".to_string()
+            } else {
+                report.join(", ")
+            };
+            return Ok(());
+        }
+        Action::SaveAndQuit => {
+            // a file that cannot be written keeps the editor open with its changes
+            let failed = workspace
+                .buffers
+                .iter_mut()
+                .filter(|buffer| buffer.dirty)
+                .find_map(|buffer| save_buffer(buffer).err());
+            match failed {
+                Some(err) => {
+                    workspace.active().message = format!("{err:#}");
+                    workspace.active().blocked = true;
+                }
+                None => workspace.should_quit = true,
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    // a confirm or paste over a finished part of the drawing asks first
+    let global_state = &workspace.buffers[workspace.active];
+    let threshold = global_state.config.overwrite_threshold;
+    let overwritten = overwritten(global_state, action, &workspace.clipboard);
+    if threshold > 0 && overwritten.len() >= threshold as usize {
+        let (left, top, right, bottom) = overwritten.iter().fold(
+            (i32::MAX, i32::MAX, i32::MIN, i32::MIN),
+            |(left, top, right, bottom), &(x, y)| {
+                (min(left, x), min(top, y), max(right, x), max(bottom, y))
+            },
+        );
+        workspace.active().message = format!(
+            "overwrite {} cells in {},{}-{},{}? (y/n)",
+            overwritten.len(),
+            left + 1,
+            top + 1,
+            right + 1,
+            bottom + 1
+        );
+        workspace.confirm_overwrite = Some(action);
+        return Ok(());
+    }
+    let global_state = workspace.active();
+    let edits = matches!(action, Action::Insert(_) | Action::Backspace);
+    let moves = matches!(
+        action,
+        Action::MoveLeft | Action::MoveRight | Action::MoveUp | Action::MoveDown
+    );
+    let fans_out = global_state.input_state == InputState::INSERT
+        && !global_state.cursors.is_empty()
+        && (edits || moves && global_state.cursors_follow);
+    if fans_out {
+        return apply_at_cursors(workspace, action);
+    }
+    apply_confirmed(workspace, action)
+}
+
+// types or moves at every extra cursor and then at the cursor, all in one undo step
+fn apply_at_cursors(workspace: &mut Workspace, action: Action) -> Result<()> {
+    let global_state = workspace.active();
+    let step = global_state.undo_step();
+    let undo = std::mem::take(&mut global_state.undo);
+    let main = global_state.current_pos;
+    // one left on the cursor itself would type everything twice
+    let mut cursors = std::mem::take(&mut global_state.cursors);
+    cursors.retain(|cursor| *cursor != main);
+    global_state.cursors_follow = true;
+    for cursor in cursors.iter_mut() {
+        workspace.active().current_pos = *cursor;
+        apply_confirmed(workspace, action)?;
+        *cursor = workspace.active().current_pos;
+    }
+    workspace.active().current_pos = main;
+    apply_confirmed(workspace, action)?;
+    let global_state = workspace.active();
+    cursors.dedup();
+    global_state.cursors = cursors;
+    global_state.undo = undo;
+    global_state.push_undo(step);
+    Ok(())
+}
+
+// glyphs an action would replace with different ones
+fn overwritten(
+    global_state: &GlobalState,
+    action: Action,
+    clipboard: &HashMap<(i32, i32), Cell>,
+) -> Vec<(i32, i32)> {
+    let (x, y) = global_state.current_pos;
+    let written: Vec<((i32, i32), char)> = match (action, global_state.input_state) {
+        (
+            Action::Confirm | Action::KeepDrawing,
+            InputState::BOX | InputState::ARROW | InputState::ELLIPSE,
+        ) => {
+            global_state.preview.iter().map(|(pos, char)| (*pos, *char)).collect()
+        }
+        (Action::Paste, _) => clipboard
+            .iter()
+            .map(|(offset, cell)| {
+                ((x.saturating_add(offset.0), y.saturating_add(offset.1)), cell.char)
+            })
+            .collect(),
+        _ => return Vec::new(),
+    };
+    written
+        .into_iter()
+        .filter(|(pos, char)| {
+            global_state
+                .diagram
+                .get(pos)
+                .is_some_and(|cell| cell.char != ' ' && cell.char != *char)
+        })
+        .map(|(pos, _)| pos)
+        .collect()
+}
+
+// the rest of apply_action, once any overwrite has been agreed to
+fn apply_confirmed(workspace: &mut Workspace, action: Action) -> Result<()> {
+    let clipboard = &mut workspace.clipboard;
+    let global_state = &mut workspace.buffers[workspace.active];
+    global_state.message.clear();
+    // moving draws in TRACE mode
+    let keeps_canvas = action.keeps_canvas() && global_state.input_state != InputState::TRACE;
+    let from = global_state.current_pos;
+    let moves = matches!(
+        action,
+        Action::MoveLeft | Action::MoveRight | Action::MoveUp | Action::MoveDown
+    );
+    // the first move of a box drops its anchor where the cursor is
+    if moves && global_state.input_state == InputState::BOX && !global_state.anchored {
+        global_state.start_pos = from;
+        global_state.anchored = true;
+    }
+    // kept to put everything back if the action writes into a locked region
+    let before = (!global_state.locked.is_empty() && !keeps_canvas).then(|| global_state.clone());
+    let step = (!keeps_canvas && action != Action::Undo).then(|| global_state.undo_step());
+    match action {
+        Action::Cancel if global_state.input_state == InputState::INSERT => {
+            if !global_state.cursors.is_empty() {
+                global_state.message = "extra cursors cleared".to_string();
+            }
+            global_state.cursors.clear();
+        }
+        // Esc ends a trace like the confirm key, the line is already drawn
+        Action::Cancel if global_state.input_state == InputState::TRACE => end_trace(global_state),
+        Action::Cancel => {
+            global_state.message = format!("{} cancelled", global_state.input_state.name());
+            global_state.preview.clear();
+            global_state.waypoints.clear();
+            global_state.input_state = InputState::INSERT;
+        }
+        Action::Undo => match global_state.undo.pop() {
+            Some((diagram, cursor, input_state)) => {
+                global_state.diagram = diagram;
+                global_state.trace.clear();
+                global_state.current_pos = cursor;
+                global_state.input_state = input_state;
+                global_state.dirty = true;
+                // undoing past the start of a group leaves it starting there
+                let len = global_state.undo.len();
+                global_state.group = global_state.group.map(|start| start.min(len));
+            }
+            None => {
+                global_state.message = "nothing to undo".to_string();
+                global_state.blocked = true;
+            }
+        },
+        Action::SystemCopy => {
+            let crop = global_state.diagram.crop_margin(global_state.config.margin);
+            global_state.message = match copy_to_system_clipboard(&crop) {
+                Ok(()) => "copied to clipboard".to_string(),
+                Err(err) => format!("copy failed: {err}"),
+            };
+        }
+        Action::SystemPaste => match read_system_clipboard() {
+            Ok(text) => paste_text(global_state, &text),
+            Err(err) => global_state.message = format!("paste failed: {err}"),
+        },
+        Action::BoxMode if global_state.input_state != InputState::BOX => {
+            global_state.enter_mode(InputState::BOX);
+        }
+        Action::ArrowMode if global_state.input_state != InputState::ARROW => {
+            global_state.enter_mode(InputState::ARROW);
+        }
+        Action::EllipseMode if global_state.input_state != InputState::ELLIPSE => {
+            global_state.enter_mode(InputState::ELLIPSE);
+        }
+        Action::SelectMode if global_state.input_state != InputState::SELECT => {
+            global_state.enter_mode(InputState::SELECT);
+        }
+        Action::TraceMode if global_state.input_state != InputState::TRACE => {
+            global_state.enter_mode(InputState::TRACE);
+        }
+        Action::ExtendLine => extend_line(global_state),
+        Action::ScaleFactor(factor) => {
+            global_state.scale = factor;
+            global_state.message = format!("stretch by {factor}");
+        }
+        Action::LockSelection => {
+            global_state.locked.push(global_state.selection());
+            global_state.message = "locked".to_string();
+        }
+        Action::UnlockSelection => {
+            let (left, top, right, bottom) = global_state.selection();
+            let count = global_state.locked.len();
+            global_state.locked.retain(|&(l, t, r, b)| {
+                l > right || r < left || t > bottom || b < top
+            });
+            global_state.message = format!("unlocked {}", count - global_state.locked.len());
+        }
+        Action::StretchWide => stretch(global_state, true),
+        Action::StretchTall => stretch(global_state, false),
+        // the first stretch leaves the widened selection for the second
+        Action::StretchBoth => {
+            stretch(global_state, true);
+            stretch(global_state, false);
+        }
+        Action::Straighten => straighten(global_state),
+        Action::InvertSelection => invert_selection(global_state),
+        Action::SwapSelection => swap_selection(global_state),
+        Action::ToggleDots => global_state.show_dots = !global_state.show_dots,
+        Action::ToggleGuideRow => {
+            let row = global_state.current_pos.1;
+            toggle_guide(&mut global_state.guide_rows, row);
+        }
+        Action::ToggleGuideColumn => {
+            let column = global_state.current_pos.0;
+            toggle_guide(&mut global_state.guide_columns, column);
+        }
+        Action::ClearGuides => {
+            global_state.guide_rows.clear();
+            global_state.guide_columns.clear();
+            global_state.message = "guides cleared".to_string();
+        }
+        Action::ToggleCursor => {
+            let pos = global_state.current_pos;
+            match global_state.cursors.iter().position(|cursor| *cursor == pos) {
+                Some(i) => {
+                    global_state.cursors.remove(i);
+                }
+                None => global_state.cursors.push(pos),
+            }
+            global_state.cursors_follow = false;
+            global_state.message = format!("{} extra cursors", global_state.cursors.len());
+        }
+        Action::ToggleLineNumbers => global_state.line_numbers = !global_state.line_numbers,
+        Action::ToggleFrame => global_state.framed = !global_state.framed,
+        Action::CenterView if !global_state.config.scroll => {
+            global_state.message = "the whole canvas is in view, scrolling is off".to_string();
+            global_state.blocked = true;
+        }
+        Action::CenterView => global_state.center_on_cursor(),
+        Action::GroupUndo => match global_state.group.take() {
+            None => {
+                global_state.group = Some(global_state.undo.len());
+                global_state.message = "grouping edits, Ctrl-W again to end".to_string();
+            }
+            Some(start) => {
+                let edits = global_state.undo.len() - start;
+                // the drawing from before the first of them undoes them all
+                global_state.undo.truncate(start + 1);
+                global_state.message = match edits {
+                    0 => "no edits to group".to_string(),
+                    1 => "grouped 1 edit".to_string(),
+                    _ => format!("grouped {edits} edits into one undo step"),
+                };
+            }
+        },
+        Action::FitContent if global_state.input_state != InputState::INSERT => {
+            global_state.message = "finish the shape first".to_string();
+            global_state.blocked = true;
+        }
+        Action::FitContent => fit_to_content(global_state),
+        Action::ResizeCanvas(dx, dy) => resize_canvas(global_state, dx, dy),
+        Action::ToggleStats => global_state.show_stats = !global_state.show_stats,
+        Action::ToggleCoords => global_state.show_coords = !global_state.show_coords,
+        Action::ToggleCrosshair => global_state.crosshair = !global_state.crosshair,
+        Action::CycleExportView => {
+            global_state.export_view = match global_state.export_view {
+                None => Some(ExportView::Ascii),
+                Some(ExportView::Ascii) => Some(ExportView::Cropped),
+                Some(ExportView::Cropped) => Some(ExportView::Comment),
+                Some(ExportView::Comment) => None,
+            };
+        }
+        Action::Copy => {
+            *clipboard = copy_cells(global_state);
+            global_state.message = "copied".to_string();
+        }
+        Action::Paste => paste_cells(global_state, clipboard),
+        Action::CycleColor => {
+            let i = PALETTE
+                .iter()
+                .position(|color| *color == global_state.active_color)
+                .unwrap_or(0);
+            global_state.active_color = PALETTE[(i + 1) % PALETTE.len()];
+            global_state.message = match global_state.active_color {
+                Some(color) => format!("color: {color}"),
+                None => "color: default".to_string(),
+            };
+        }
+        Action::Save => {
+            if let Err(err) = save_buffer(global_state) {
+                global_state.message = format!("{err:#}");
+                global_state.blocked = true;
+            }
+        }
+        Action::Insert(_)
+            if global_state.config.protect_lines
+                && global_state
+                    .diagram
+                    .get(&global_state.current_pos)
+                    .is_some_and(|cell| glyph_sides(cell.char).is_some()) =>
+        {
+            global_state.message = "not typing over a line".to_string();
+            global_state.blocked = true;
+        }
+        Action::Insert(_) if global_state.at_edge() > 1 => {
+            // the cell would be off the canvas, typing on there would silently overwrite it
+            global_state.message = "at the edge".to_string();
+            global_state.blocked = true;
+        }
+        Action::Insert(char) if global_state.config.text_down => {
+            let cell = global_state.colored(char);
+            global_state.diagram.insert(global_state.current_pos, cell);
+            global_state.dirty = true;
+            if global_state.current_pos.1 < global_state.canvas_size().1 {
+                global_state.current_pos.1 += 1;
+            }
+        }
+        Action::Insert(char) => {
+            let cell = global_state.colored(char);
+            global_state.diagram.insert(global_state.current_pos, cell);
+            global_state.dirty = true;
+            if global_state.current_pos.0 < global_state.canvas_size().0 {
+                global_state.current_pos.0 += global_state.advance(char);
+            }
+            let width = global_state.page_size().0;
+            let at_edge = width > 0 && global_state.current_pos.0 >= width;
+            if global_state.config.wrap && at_edge {
+                wrap_word(global_state);
+            }
+        }
+        Action::PickBrush => {
+            let (x, y) = global_state.current_pos;
+            // the right half of a wide glyph picks the glyph
+            let picked = global_state.diagram.get(&(x, y)).or_else(|| {
+                let left = global_state.diagram.get(&(x - 1, y));
+                left.filter(|cell| global_state.advance(cell.char) == 2)
+            });
+            match picked.map(|cell| cell.char).filter(|char| *char != ' ') {
+                Some(char) => {
+                    global_state.active_char = Some(char);
+                    global_state.message = format!("picked {char}, Alt-B restores the brush");
+                }
+                None => {
+                    global_state.message = "nothing under the cursor to pick".to_string();
+                    global_state.blocked = true;
+                }
+            }
+        }
+        Action::Stamp if global_state.drawing_text().is_empty() => {
+            global_state.message = "no brush, set one with Alt-B".to_string();
+        }
+        Action::Stamp => {
+            let brush = global_state.drawing_text();
+            global_state.current_pos.0 = place_text(global_state, global_state.current_pos, &brush);
+            global_state.dirty = true;
+        }
+        Action::ToggleTextDirection => {
+            global_state.config.text_down = !global_state.config.text_down;
+            global_state.message = if global_state.config.text_down {
+                "typing downwards".to_string()
+            } else {
+                "typing to the right".to_string()
+            };
+        }
+        Action::ToggleWrap => {
+            global_state.config.wrap = !global_state.config.wrap;
+            global_state.message = if global_state.config.wrap {
+                "wrap on".to_string()
+            } else {
+                "wrap off".to_string()
+            };
+        }
+        Action::AddWaypoint => global_state.waypoints.push(global_state.current_pos),
+        Action::RemoveWaypoint => {
+            global_state.waypoints.pop();
+        }
+        Action::ExportDot => {
+            let path = global_state.path.with_extension("dot");
+            let dot = export_dot(&global_state.diagram).and_then(|dot| {
+                fs::write(&path, dot).with_context(|| format!("cannot write {}", path.display()))
+            });
+            global_state.message = match dot {
+                Ok(()) => {
+                    let boxes = global_state.diagram.boxes();
+                    let (nodes, edges) = (boxes.len(), global_state.diagram.edges(&boxes).len());
+                    format!("exported {} ({nodes} nodes, {edges} edges)", path.display())
+                }
+                Err(err) => format!("dot export failed: {err:#}"),
+            };
+        }
+        Action::ExportAscii => {
+            let path = global_state.path.with_extension("ascii.txt");
+            global_state.message = match export_ascii(global_state, &path) {
+                Ok(()) => format!("exported {}", path.display()),
+                Err(err) => format!("ascii export failed: {err:#}"),
+            };
+        }
+        Action::ExportComment => {
+            let path = global_state.path.with_extension("comment.txt");
+            let margin = global_state.config.margin;
+            let block = export_comment(&global_state.diagram, marker(global_state), margin);
+            global_state.message = match fs::write(&path, block) {
+                Ok(()) => format!("exported {}", path.display()),
+                Err(err) => format!("comment export failed: {}: {err}", path.display()),
+            };
+        }
+        Action::ExportSelection => {
+            let path = global_state.path.with_extension("selection.txt");
+            let area = global_state.selection();
+            let region = render(&global_state.diagram, &Glyphs::new(), area, None);
+            global_state.message = match fs::write(&path, trim_trailing_spaces(&region)) {
+                Ok(()) => format!("exported {}", path.display()),
+                Err(err) => format!("selection export failed: {}: {err}", path.display()),
+            };
+        }
+        Action::Diagnostics => {
+            let path = global_state.path.with_extension("diagnostics.txt");
+            global_state.message = match fs::write(&path, diagnostics(global_state)) {
+                Ok(()) => format!("wrote {}", path.display()),
+                Err(err) => format!("diagnostics failed: {}: {err}", path.display()),
+            };
+        }
+        Action::ShowRow => {
+            let row = global_state.current_pos.1;
+            global_state.message = format!("row {}: {}", row + 1, global_state.row_text(row));
+        }
+        Action::Complete => expand_mnemonic(global_state),
+        Action::DeleteShape => {
+            global_state.message = match delete_shape(global_state) {
+                Some(shape) => format!("deleted {shape}"),
+                None => "no box or line under the cursor".to_string(),
+            };
+        }
+        Action::UpgradeAscii => {
+            let upgraded = global_state.diagram.upgrade_ascii(&global_state.config.glyphs);
+            global_state.dirty |= upgraded > 0;
+            global_state.message = format!("upgraded {upgraded} ascii glyphs");
+        }
+        Action::NormalizeLines => {
+            let fixed = global_state.diagram.normalize_lines();
+            global_state.dirty |= fixed > 0;
+            global_state.message = format!("redrew {fixed} line glyphs");
+        }
+        Action::NextBox => jump_to_box(global_state, true),
+        Action::PrevBox => jump_to_box(global_state, false),
+        Action::FitBox => fit_box(global_state),
+        // before the box is anchored the cursor moves without dragging
+        Action::MoveAnchor(dx, dy)
+            if global_state.input_state == InputState::BOX && !global_state.anchored =>
+        {
+            let (width, height) = global_state.canvas_size();
+            let (x, y) = global_state.current_pos;
+            global_state.current_pos = ((x + dx).clamp(0, width), (y + dy).clamp(0, height));
+            global_state.start_pos = global_state.current_pos;
+        }
+        // the corner opposite the cursor, or the centre when growing from it
+        Action::MoveAnchor(dx, dy)
+            if matches!(global_state.input_state, InputState::BOX | InputState::ELLIPSE) =>
+        {
+            let (width, height) = global_state.canvas_size();
+            let (x, y) = global_state.start_pos;
+            global_state.start_pos = ((x + dx).clamp(0, width), (y + dy).clamp(0, height));
+        }
+        Action::ToggleBoxCenter => {
+            global_state.box_from_center = !global_state.box_from_center;
+            global_state.message = if global_state.box_from_center {
+                "box from centre".to_string()
+            } else {
+                "box from corner".to_string()
+            };
+        }
+        Action::ToggleBoxSquare => {
+            global_state.box_square = !global_state.box_square;
+            global_state.message = if global_state.box_square {
+                "square on screen".to_string()
+            } else {
+                "any shape".to_string()
+            };
+        }
+        Action::ToggleBoxSide(side) if global_state.box_sides == side => {
+            global_state.message = "a box needs at least one side".to_string();
+            global_state.blocked = true;
+        }
+        Action::ToggleBoxSide(side) => {
+            global_state.box_sides ^= side;
+            let names = [(UP, "top"), (DOWN, "bottom"), (LEFT, "left"), (RIGHT, "right")];
+            let drawn: Vec<&str> = names
+                .iter()
+                .filter(|(side, _)| global_state.box_sides & side != 0)
+                .map(|(_, name)| *name)
+                .collect();
+            global_state.message = format!("box sides: {}", drawn.join(" "));
+        }
+        Action::Backspace if global_state.config.text_down && global_state.current_pos.1 > 0 => {
+            global_state.current_pos.1 -= 1;
+            global_state.diagram.remove(&global_state.current_pos);
+            global_state.dirty = true;
+        }
+        Action::Backspace if !global_state.config.text_down && global_state.current_pos.0 > 0 => {
+            global_state.current_pos.0 -= 1;
+            global_state.diagram.remove(&global_state.current_pos);
+            global_state.dirty = true;
+        }
+        Action::MoveLeft
+            if global_state.wraps()
+                && global_state.current_pos.0 == 0
+                && global_state.current_pos.1 > 0 =>
+        {
+            // a scrolling canvas has no right edge, so land after the text
+            let row = global_state.current_pos.1 - 1;
+            let end = if global_state.config.scroll {
+                global_state.row_end(row)
+            } else {
+                global_state.line_end(row)
+            };
+            global_state.prev_pos = global_state.current_pos;
+            global_state.current_pos = (end, row);
+        }
+        Action::MoveRight
+            if global_state.wraps()
+                && global_state.current_pos.0
+                    >= global_state.line_end(global_state.current_pos.1)
+                && global_state.current_pos.1 < global_state.canvas_size().1 =>
+        {
+            global_state.prev_pos = global_state.current_pos;
+            global_state.current_pos = (0, global_state.current_pos.1 + 1);
+        }
+        Action::MoveLeft if global_state.current_pos.0 > 0 => {
+            global_state.prev_pos = global_state.current_pos;
+            global_state.current_pos.0 -= 1;
+        }
+        Action::MoveRight
+            if global_state.bounded()
+                && global_state.current_pos.0
+                    >= global_state.row_end(global_state.current_pos.1) => {}
+        Action::MoveRight if global_state.current_pos.0 < global_state.canvas_size().0 => {
+            global_state.prev_pos = global_state.current_pos;
+            global_state.current_pos.0 += 1;
+        }
+        Action::MoveUp if global_state.current_pos.1 > 0 => {
+            global_state.prev_pos = global_state.current_pos;
+            global_state.current_pos.1 -= 1;
+            if global_state.bounded() {
+                let end = global_state.row_end(global_state.current_pos.1);
+                global_state.current_pos.0 = global_state.current_pos.0.min(end);
+            }
+        }
+        Action::MoveDown if global_state.current_pos.1 < global_state.canvas_size().1 => {
+            global_state.prev_pos = global_state.current_pos;
+            global_state.current_pos.1 += 1;
+            if global_state.bounded() {
+                let end = global_state.row_end(global_state.current_pos.1);
+                global_state.current_pos.0 = global_state.current_pos.0.min(end);
+            }
+        }
+        Action::Confirm | Action::KeepDrawing => {
+            let mode = global_state.input_state;
+            match mode {
+                InputState::BOX => {
+                    // a box needs at least two rows and two columns, otherwise keep dragging
+                    let (left, top, right, bottom) = box_bounds(global_state);
+                    if left == right || top == bottom {
+                        global_state.message = "box has no area, drag a corner first".to_string();
+                        global_state.blocked = true;
+                        return Ok(());
+                    }
+                    let overlap = merge_borders(global_state);
+                    keep_heads(global_state);
+                    // confirm box 
+                    global_state.diagram.draw(&global_state.preview, global_state.active_color);
+                    global_state.preview.clear();
+                    global_state.input_state = InputState::INSERT;
+                    global_state.dirty = true;
+                    if overlap > 0 {
+                        global_state.message = match global_state.config.box_overlap {
+                            BoxOverlap::Merge => format!("joined the box to {overlap} line cells"),
+                            _ => format!("box border overlaps {overlap} line cells"),
+                        };
+                    }
+                },
+                InputState::INSERT => {
+                    let row = global_state.current_pos.1;
+                    let indent = global_state
+                        .diagram
+                        .iter()
+                        .filter(|((_, y), cell)| *y == row && cell.char != ' ')
+                        .map(|((x, _), _)| *x)
+                        .min()
+                        .filter(|_| global_state.config.auto_indent);
+                    // new line 
+                    if global_state.current_pos.1 < global_state.canvas_size().1 {
+                        global_state.current_pos.1 += 1;
+                    }
+                    if !global_state.config.enter_keeps_column {
+                        global_state.current_pos.0 = indent.unwrap_or(0);
+                    }
+                }
+                InputState::ARROW => {
+                    if snap_endpoint(global_state) {
+                        build_arrow_preview(global_state);
+                    }
+                    attach_ends(global_state);
+                    keep_heads(global_state);
+                    // confirm arrow  
+                    global_state.diagram.draw(&global_state.preview, global_state.active_color);
+                    global_state.preview.clear();
+                    global_state.waypoints.clear();
+                    global_state.input_state = InputState::INSERT;
+                    global_state.dirty = true;
+                },
+                InputState::SELECT => global_state.input_state = InputState::INSERT,
+                InputState::TRACE => end_trace(global_state),
+                InputState::ELLIPSE => {
+                    keep_heads(global_state);
+                    global_state.diagram.draw(&global_state.preview, global_state.active_color);
+                    global_state.preview.clear();
+                    global_state.input_state = InputState::INSERT;
+                    global_state.dirty = true;
+                }
+            }
+            // the next shape starts at the cursor in the same mode
+            if action == Action::KeepDrawing && global_state.input_state != mode {
+                global_state.enter_mode(mode);
+            }
+        }
+        _ => {}
+    }
+
+    // against the canvas edge, or the end of the text with bounded_cursor
+    if moves && global_state.current_pos == from {
+        global_state.blocked = true;
+    }
+    if global_state.input_state == InputState::TRACE && global_state.current_pos != from {
+        trace_step(global_state, from);
+    }
+    if let Some(before) = before {
+        reject_locked_edit(global_state, before);
+    }
+    if let Some(step) = step {
+        global_state.push_undo(step);
+    }
+    if !keeps_canvas {
+        global_state.canvas_stale = true;
+    }
+    update_preview(global_state);
+    Ok(())
+}
+
+// adds a guide at `at` or takes away the one already there
+fn toggle_guide(guides: &mut Vec<i32>, at: i32) {
+    match guides.iter().position(|guide| *guide == at) {
+        Some(i) => {
+            guides.remove(i);
+        }
+        None => guides.push(at),
+    }
+}
+
+// the terminal bell, for a keystroke that was read but could not do anything
+fn ring_bell() {
+    let mut stdout = io::stdout();
+    let _ = stdout.write_all(b"\x07").and_then(|()| stdout.flush());
+}
+
+// joins `from` to the cursor with a line, turning it into corners and junctions where it
+// turns or crosses lines, each move is its own undo step
+fn trace_step(global_state: &mut GlobalState, from: (i32, i32)) {
+    // a jump such as Alt-N to the next box is walked cell by cell, horizontally first, so the
+    // line has no gaps
+    for leg in elbow(from, global_state.current_pos).windows(2) {
+        let direction = side((leg[1].0 - leg[0].0, leg[1].1 - leg[0].1));
+        for (pos, side) in [(leg[0], direction), (leg[1], opposite(direction))] {
+            let under = global_state.diagram.get(&pos).and_then(|cell| glyph_sides(cell.char));
+            let sides = global_state.trace.entry(pos).or_insert(under.unwrap_or(0));
+            *sides |= side;
+            let sides = *sides;
+            let cell = global_state.colored(global_state.glyphs.line(sides));
+            global_state.diagram.insert(pos, cell);
+        }
+    }
+    global_state.dirty = true;
+}
+
+// TRACE mode from the end of the line or arrow under the cursor, the end only keeps the sides
+// its neighbours join so turning off it makes a corner rather than a tee
+fn extend_line(global_state: &mut GlobalState) {
+    let pos = global_state.current_pos;
+    let joined = |side: u8| {
+        let next = global_state.diagram.get(&step(pos, side)).map(|cell| cell.char);
+        let back = next.and_then(|char| glyph_sides(char).or_else(|| head_entry(char)));
+        back.is_some_and(|sides| sides & opposite(side) != 0)
+    };
+    let sides = [UP, DOWN, LEFT, RIGHT]
+        .into_iter()
+        .filter(|&side| joined(side))
+        .fold(0, |sides, side| sides | side);
+    let under = global_state.diagram.get(&pos).map(|cell| cell.char);
+    let head = under.and_then(head_entry);
+    let end = match (head, under.and_then(glyph_sides)) {
+        (Some(entry), _) if sides & entry != 0 => entry,
+        (None, Some(_)) if sides.count_ones() == 1 => sides,
+        _ => {
+            global_state.message = "not on the end of a line or arrow".to_string();
+            global_state.blocked = true;
+            return;
+        }
+    };
+    global_state.enter_mode(InputState::TRACE);
+    global_state.extend_head = head.is_some();
+    global_state.trace.insert(pos, end);
+    let cell = global_state.colored(global_state.glyphs.line(end));
+    global_state.diagram.insert(pos, cell);
+    global_state.message = "extending, Enter or Esc ends it".to_string();
+}
+
+// back to INSERT, an extended arrow gets its head on the new tip pointing the way it went
+fn end_trace(global_state: &mut GlobalState) {
+    global_state.input_state = InputState::INSERT;
+    if !std::mem::take(&mut global_state.extend_head) {
+        return;
+    }
+    let tip = global_state.current_pos;
+    let Some(&entry) = global_state.trace.get(&tip).filter(|sides| sides.count_ones() == 1) else {
+        return;
+    };
+    let (x, y) = step((0, 0), opposite(entry));
+    let head = global_state.colored(global_state.glyphs.head((x, y)));
+    global_state.diagram.insert(tip, head);
+    global_state.dirty = true;
+}
+
+// redraws the lines and heads inside the selection as one straight line along the axis its ends
+// are furthest apart on, on the row or column most of it is on, a head at either end stays
+fn straighten(global_state: &mut GlobalState) {
+    let (left, top, right, bottom) = global_state.selection();
+    let head = |char: char| head_side(char).is_some();
+    let mut cells: Vec<((i32, i32), Cell)> = global_state
+        .diagram
+        .iter()
+        .filter(|((x, y), _)| (left..=right).contains(x) && (top..=bottom).contains(y))
+        .filter(|(_, cell)| head(cell.char) || glyph_sides(cell.char).is_some())
+        .map(|(pos, cell)| (*pos, *cell))
+        .collect();
+    if cells.len() < 2 {
+        global_state.message = "no line in the selection to straighten".to_string();
+        global_state.blocked = true;
+        return;
+    }
+    cells.sort_by_key(|((x, y), _)| (*y, *x));
+    let xs = cells.iter().map(|((x, _), _)| *x);
+    let ys = cells.iter().map(|((_, y), _)| *y);
+    let (first_x, last_x) = (xs.clone().min().unwrap(), xs.max().unwrap());
+    let (first_y, last_y) = (ys.clone().min().unwrap(), ys.max().unwrap());
+    let horizontal = last_x - first_x >= last_y - first_y;
+    // (along, across) for a horizontal line, the other way round for a vertical one
+    let split = |(x, y): (i32, i32)| if horizontal { (x, y) } else { (y, x) };
+    let (start, end) = if horizontal { (first_x, last_x) } else { (first_y, last_y) };
+    let mut rows: HashMap<i32, usize> = HashMap::new();
+    for (pos, _) in &cells {
+        *rows.entry(split(*pos).1).or_default() += 1;
+    }
+    let across = rows.into_iter().max_by_key(|&(row, count)| (count, -row)).unwrap().0;
+    let heads: Vec<i32> = cells
+        .iter()
+        .filter(|(_, cell)| head(cell.char))
+        .map(|(pos, _)| split(*pos).0)
+        .collect();
+    let near_start = heads.iter().any(|head| *head - start <= end - *head);
+    let near_end = heads.iter().any(|head| *head - start > end - *head);
+    let sample = cells.iter().map(|(_, cell)| *cell).find(|cell| !head(cell.char));
+    let line = sample.map_or(global_state.glyphs.line(LEFT | RIGHT), |cell| {
+        glyph_like(cell.char, if horizontal { LEFT | RIGHT } else { UP | DOWN })
+    });
+    let fg = cells[0].1.fg;
+    for (pos, _) in &cells {
+        global_state.diagram.remove(pos);
+    }
+    for along in start..=end {
+        let glyphs = &global_state.glyphs;
+        let char = match (horizontal, along == start && near_start, along == end && near_end) {
+            (true, true, _) => glyphs.head((-1, 0)),
+            (true, _, true) => glyphs.head((1, 0)),
+            (false, true, _) => glyphs.head((0, -1)),
+            (false, _, true) => glyphs.head((0, 1)),
+            _ => line,
+        };
+        let pos = if horizontal { (along, across) } else { (across, along) };
+        global_state.diagram.insert(pos, Cell { char, fg });
+    }
+    global_state.dirty = true;
+    global_state.message = format!("straightened {} cells", end - start + 1);
+}
+
+// crops the canvas to the drawing: it moves so the margin is all that is left above and to the
+// left of it, and the canvas ends the margin past it, so saves and exports come out that size
+fn fit_to_content(global_state: &mut GlobalState) {
+    let Some((left, top, right, bottom)) = global_state.bounds() else {
+        global_state.message = "nothing drawn to fit to".to_string();
+        global_state.blocked = true;
+        return;
+    };
+    let (margin_left, margin_top, margin_right, margin_bottom) = global_state.config.margin;
+    let (dx, dy) = (margin_left - left, margin_top - top);
+    let moved = |(x, y): (i32, i32)| (x + dx, y + dy);
+    let cells: Vec<_> = global_state.diagram.drain().collect();
+    for (pos, cell) in cells {
+        global_state.diagram.insert(moved(pos), cell);
+    }
+    global_state.tabs = global_state.tabs.iter().map(|pos| moved(*pos)).collect();
+    for (l, t, r, b) in &mut global_state.locked {
+        (*l, *t, *r, *b) = (*l + dx, *t + dy, *r + dx, *b + dy);
+    }
+    for row in &mut global_state.guide_rows {
+        *row += dy;
+    }
+    for column in &mut global_state.guide_columns {
+        *column += dx;
+    }
+    let size = (
+        right - left + 1 + margin_left + margin_right,
+        bottom - top + 1 + margin_top + margin_bottom,
+    );
+    global_state.config.width = Some(size.0);
+    global_state.config.height = Some(size.1);
+    global_state.config.scroll = true;
+    let clamp = |(x, y): (i32, i32)| ((x + dx).clamp(0, size.0 - 1), (y + dy).clamp(0, size.1 - 1));
+    global_state.current_pos = clamp(global_state.current_pos);
+    global_state.cursors = global_state.cursors.iter().map(|pos| clamp(*pos)).collect();
+    global_state.viewport = (0, 0);
+    global_state.dirty |= (dx, dy) != (0, 0);
+    global_state.message = format!("fitted the canvas to {}x{}", size.0, size.1);
+}
+
+// sets the canvas size as --width and --height would, never smaller than the glyphs on it
+fn resize_canvas(global_state: &mut GlobalState, dx: i32, dy: i32) {
+    let (width, height) = global_state.page_size();
+    let (right, bottom) = global_state.bounds().map_or((0, 0), |(_, _, r, b)| (r + 1, b + 1));
+    let max = global_state.config.max_canvas();
+    let size = (
+        (width + dx).clamp(right.max(1), max),
+        (height + dy).clamp(bottom.max(1), max),
+    );
+    if size == (width, height) && global_state.config.width.is_some() {
+        global_state.message = if dx < 0 || dy < 0 {
+            format!("the glyphs need {right}x{bottom}, not shrinking past them")
+        } else {
+            format!("already at max_canvas {max}")
+        };
+        global_state.blocked = true;
+        return;
+    }
+    global_state.config.width = Some(size.0);
+    global_state.config.height = Some(size.1);
+    // a fixed size scrolls, as with --width and --height
+    global_state.config.scroll = true;
+    let (x, y) = global_state.current_pos;
+    global_state.current_pos = (x.min(size.0 - 1), y.min(size.1 - 1));
+    global_state.message = format!("canvas {}x{}", size.0, size.1);
+}
+
+// the first press marks the selection, the second swaps the marked cells with the ones
+// selected then, which have to be the same size and apart
+fn swap_selection(global_state: &mut GlobalState) {
+    let area = global_state.selection();
+    let Some(mark) = global_state.swap_mark.filter(|mark| *mark != area) else {
+        global_state.swap_mark = match global_state.swap_mark {
+            Some(_) => None,
+            None => Some(area),
+        };
+        global_state.message = match global_state.swap_mark {
+            Some(_) => "marked, select the other part and press x to swap".to_string(),
+            None => "swap mark cleared".to_string(),
+        };
+        return;
+    };
+    let size = |(left, top, right, bottom): (i32, i32, i32, i32)| (right - left, bottom - top);
+    let (mark_width, mark_height) = size(mark);
+    if size(area) != (mark_width, mark_height) {
+        global_state.message = format!(
+            "the marked part is {}x{}, select the same size to swap",
+            mark_width + 1,
+            mark_height + 1
+        );
+        global_state.blocked = true;
+        return;
+    }
+    let overlaps = area.0 <= mark.2 && mark.0 <= area.2 && area.1 <= mark.3 && mark.1 <= area.3;
+    if overlaps {
+        global_state.message = "the two parts overlap, not swapping".to_string();
+        global_state.blocked = true;
+        return;
+    }
+    let inside = |(left, top, right, bottom): (i32, i32, i32, i32), (x, y): (i32, i32)| {
+        (left..=right).contains(&x) && (top..=bottom).contains(&y)
+    };
+    let (dx, dy) = (area.0 - mark.0, area.1 - mark.1);
+    let taken: Vec<_> = global_state
+        .diagram
+        .keys()
+        .filter(|pos| inside(mark, **pos) || inside(area, **pos))
+        .copied()
+        .collect();
+    let cells: Vec<_> = taken
+        .into_iter()
+        .filter_map(|pos| Some((pos, global_state.diagram.remove(&pos)?)))
+        .collect();
+    for ((x, y), cell) in cells {
+        let to = if inside(mark, (x, y)) { (x + dx, y + dy) } else { (x - dx, y - dy) };
+        global_state.diagram.insert(to, cell);
+    }
+    global_state.swap_mark = None;
+    global_state.dirty = true;
+    global_state.message = "swapped".to_string();
+}
+
+// empties every taken cell of the selection and fills every empty one with the picked glyph or
+// the brush's first character, the right half of a wide glyph counts as taken
+fn invert_selection(global_state: &mut GlobalState) {
+    let Some(fill) = global_state.drawing_text().chars().next() else {
+        global_state.message = "no brush to fill with, set one with Alt-B".to_string();
+        global_state.blocked = true;
+        return;
+    };
+    if global_state.advance(fill) > 1 {
+        global_state.message = format!("{fill} is wider than a cell, pick a narrow brush");
+        global_state.blocked = true;
+        return;
+    }
+    let (left, top, right, bottom) = global_state.selection();
+    let mut taken = HashSet::new();
+    for ((x, y), cell) in global_state.diagram.iter() {
+        if (left - 1..=right).contains(x) && (top..=bottom).contains(y) {
+            taken.extend((*x..*x + global_state.advance(cell.char)).map(|x| (x, *y)));
+        }
+    }
+    let (mut emptied, mut filled) = (0, 0);
+    for y in top..=bottom {
+        for x in left..=right {
+            if !taken.contains(&(x, y)) {
+                let cell = global_state.colored(fill);
+                global_state.diagram.insert((x, y), cell);
+                filled += 1;
+            } else if global_state.diagram.remove(&(x, y)).is_some() {
+                emptied += 1;
+            }
+        }
+    }
+    global_state.dirty = true;
+    global_state.message = format!("inverted, {emptied} glyphs emptied and {filled} cells filled");
+}
+
+// moves the word ending at the cursor to the start of the next row, a word filling the whole
+// row is broken where it is
+fn wrap_word(global_state: &mut GlobalState) {
+    let (end, y) = global_state.current_pos;
+    if y >= global_state.canvas_size().1 {
+        return;
+    }
+    let letter = |x: i32| {
+        global_state
+            .diagram
+            .get(&(x, y))
+            .filter(|cell| cell.char != ' ')
+            .map(|cell| global_state.advance(cell.char))
+    };
+    let mut start = end;
+    while start > 0 {
+        if letter(start - 1).is_some() {
+            start -= 1;
+        } else if start > 1 && letter(start - 2) == Some(2) {
+            // right half of a wide glyph
+            start -= 2;
+        } else {
+            break;
+        }
+    }
+    if start == 0 {
+        start = end;
+    }
+    for x in start..end {
+        if let Some(cell) = global_state.diagram.remove(&(x, y)) {
+            global_state.diagram.insert((x - start, y + 1), cell);
+        }
+    }
+    global_state.prev_pos = global_state.current_pos;
+    global_state.current_pos = (end - start, y + 1);
+}
+
+// spreads the columns (or rows) of the selection `scale` times further apart, the gaps after a
+// line glyph that continues onwards are filled with straight lines so borders stay joined, the
+// content past the selection on the same rows (or columns) moves out of the way
+fn stretch(global_state: &mut GlobalState, horizontal: bool) {
+    let (left, top, right, bottom) = global_state.selection();
+    let factor = global_state.scale;
+    // (along, across) the stretch direction, and back
+    let split = |(x, y): (i32, i32)| if horizontal { (x, y) } else { (y, x) };
+    let (low, cross_low) = split((left, top));
+    let (high, cross_high) = split((right, bottom));
+    let (onwards, straight) = if horizontal {
+        (RIGHT, LEFT | RIGHT)
+    } else {
+        (DOWN, UP | DOWN)
+    };
+    let extra = (high - low) * (factor - 1);
+
+    let band: Vec<((i32, i32), Cell)> = global_state
+        .diagram
+        .iter()
+        .map(|(pos, cell)| (split(*pos), *cell))
+        .filter(|((along, across), _)| *along >= low && (cross_low..=cross_high).contains(across))
+        .collect();
+    for (pos, _) in &band {
+        global_state.diagram.remove(&split(*pos));
+    }
+    for ((along, across), cell) in band {
+        if along > high {
+            global_state.diagram.insert(split((along + extra, across)), cell);
+            continue;
+        }
+        let base = low + (along - low) * factor;
+        global_state.diagram.insert(split((base, across)), cell);
+        if glyph_sides(cell.char).is_some_and(|sides| sides & onwards != 0) {
+            let line = Cell { char: glyph_like(cell.char, straight), fg: cell.fg };
+            for copy in base + 1..base + factor {
+                global_state.diagram.insert(split((copy, across)), line);
+            }
+        }
+    }
+
+    let end = split((high + extra, cross_high));
+    global_state.start_pos = (left, top);
+    global_state.prev_pos = global_state.current_pos;
+    global_state.current_pos = (
+        min(end.0, global_state.canvas_size().0),
+        min(end.1, global_state.canvas_size().1),
+    );
+    global_state.dirty = true;
+    global_state.message = format!("stretched by {factor}");
+}
+
+// moves an arrow end lying in empty space onto the nearest glyph within snap_radius, ties go
+// to the glyph in line with the end, then top to bottom and left to right
+fn snap_endpoint(global_state: &mut GlobalState) -> bool {
+    let radius = global_state.config.snap_radius;
+    let (x, y) = global_state.current_pos;
+    if radius == 0 || global_state.diagram.contains_key(&(x, y)) {
+        return false;
+    }
+    let nearest = (y - radius..=y + radius)
+        .flat_map(|ny| (x - radius..=x + radius).map(move |nx| (nx, ny)))
+        .filter(|pos| global_state.diagram.get(pos).is_some_and(|cell| cell.char != ' '))
+        .min_by_key(|&(nx, ny)| {
+            let (dx, dy) = ((nx - x).abs(), (ny - y).abs());
+            (dx.max(dy), dx + dy, ny, nx)
+        });
+    match nearest {
+        Some(pos) => {
+            global_state.current_pos = pos;
+            true
+        }
+        None => false,
+    }
+}
+
+// undoes an edit that changed any locked cell, cursor and mode included
+fn reject_locked_edit(global_state: &mut GlobalState, before: GlobalState) {
+    let touched = global_state.locked.iter().any(|&(left, top, right, bottom)| {
+        (top..=bottom).any(|y| {
+            (left..=right).any(|x| global_state.diagram.get(&(x, y)) != before.diagram.get(&(x, y)))
+        })
+    });
+    if touched {
+        *global_state = before;
+        global_state.message = "region is locked".to_string();
+        global_state.blocked = true;
+    }
+}
+
+// resizes the box being dragged to wrap the glyphs inside it, a space of padding at either side
+fn fit_box(global_state: &mut GlobalState) {
+    let (left, top, right, bottom) = box_bounds(global_state);
+    let inside = global_state
+        .diagram
+        .iter()
+        .filter(|((x, y), _)| (left..=right).contains(x) && (top..=bottom).contains(y))
+        .map(|(&(x, y), cell)| (x, y, x + global_state.advance(cell.char) - 1));
+    let content = inside.fold(None, |bounds, (x, y, end)| {
+        let (left, top, right, bottom) = bounds.unwrap_or((x, y, end, y));
+        Some((min(left, x), min(top, y), max(right, end), max(bottom, y)))
+    });
+    let Some((left, top, right, bottom)) = content else {
+        global_state.message = "nothing inside the box to fit".to_string();
+        return;
+    };
+    // content against the top or left edge of the canvas gets less padding
+    global_state.box_from_center = false;
+    global_state.anchored = true;
+    global_state.start_pos = ((left - 2).max(0), (top - 1).max(0));
+    global_state.prev_pos = global_state.current_pos;
+    global_state.current_pos = (right + 2, bottom + 1);
+}
+
+// counts the border cells of the box preview that land on committed lines, with box_overlap
+// set to merge they become the junction of both
+fn merge_borders(global_state: &mut GlobalState) -> usize {
+    let mut overlap = 0;
+    for (pos, char) in global_state.preview.iter_mut() {
+        let under = global_state.diagram.get(pos).and_then(|cell| glyph_sides(cell.char));
+        let (Some(under), Some(sides)) = (under, glyph_sides(*char)) else {
+            continue;
+        };
+        overlap += 1;
+        if global_state.config.box_overlap == BoxOverlap::Merge {
+            *char = global_state.glyphs.line(sides | under);
+        }
+    }
+    overlap
+}
+
+// an arrow starting or ending on a line glyph, a box border say, joins it with a tee instead of
+// covering it or sitting next to it, the head steps back a cell to point at the tee
+fn attach_ends(global_state: &mut GlobalState) {
+    let (start, end) = (global_state.start_pos, global_state.current_pos);
+    let under = |pos: (i32, i32)| {
+        let cell = global_state.diagram.get(&pos)?;
+        glyph_sides(cell.char).map(|sides| (cell.char, sides))
+    };
+    if start == end {
+        return;
+    }
+    if let (Some((char, sides)), Some(&head)) = (under(end), global_state.preview.get(&end)) {
+        let entry = head_entry(head);
+        let behind = entry.map(|entry| step(end, entry));
+        let body = behind.and_then(|pos| global_state.preview.get(&pos).copied());
+        if let (Some(entry), Some(behind), Some(_)) = (entry, behind, body.and_then(glyph_sides)) {
+            global_state.preview.insert(behind, head);
+            global_state.preview.insert(end, glyph_like(char, sides | entry));
+        }
+    }
+    // the first leg runs horizontally first, like elbow
+    if let Some((char, sides)) = under(start) {
+        let next = global_state.waypoints.first().copied().unwrap_or(end);
+        let leaves = if next.0 != start.0 {
+            side(((next.0 - start.0).signum(), 0))
+        } else {
+            side((0, (next.1 - start.1).signum()))
+        };
+        global_state.preview.insert(start, glyph_like(char, sides | leaves));
+    }
+}
+
+// the side an arrow head is entered from, None for anything else
+fn head_entry(char: char) -> Option<u8> {
+    head_side(char).map(opposite)
+}
+
+// a committed arrow head stays when a shape is drawn over it, only erasing takes it away, a
+// new head landing on one steps back a cell so both arrows point at the same place
+fn keep_heads(global_state: &mut GlobalState) {
+    let heads: Vec<(i32, i32)> = global_state
+        .preview
+        .keys()
+        .filter(|pos| {
+            let committed = global_state.diagram.get(pos);
+            committed.is_some_and(|cell| head_entry(cell.char).is_some())
+        })
+        .copied()
+        .collect();
+    for pos in heads {
+        let Some(head) = global_state.preview.remove(&pos) else {
+            continue;
+        };
+        if let Some(entry) = head_entry(head) {
+            let behind = step(pos, entry);
+            if global_state.preview.get(&behind).copied().and_then(glyph_sides).is_some() {
+                global_state.preview.insert(behind, head);
+            }
+        }
+    }
+}
+
+// corners of the box being dragged as (left, top, right, bottom)
+fn box_bounds(global_state: &GlobalState) -> (i32, i32, i32, i32) {
+    let (start, mut current) = (global_state.start_pos, global_state.current_pos);
+    if global_state.box_square {
+        // cells are about twice as tall as wide, the longer side on screen wins
+        let (dx, dy) = (current.0 - start.0, current.1 - start.1);
+        let rows = dy.abs().max((dx.abs() + 1) / 2);
+        let (sign_x, sign_y) = (if dx < 0 { -1 } else { 1 }, if dy < 0 { -1 } else { 1 });
+        current = ((start.0 + sign_x * rows * 2).max(0), (start.1 + sign_y * rows).max(0));
+    }
+    if global_state.box_from_center {
+        // start_pos is the centre, the cursor sets the half extents
+        // the canvas still starts at 0, so the box is cut off at the top-left edge
+        let dx = (start.0 - current.0).abs();
+        let dy = (start.1 - current.1).abs();
+        ((start.0 - dx).max(0), (start.1 - dy).max(0), start.0 + dx, start.1 + dy)
+    } else {
+        (
+            min(current.0, start.0),
+            min(current.1, start.1),
+            max(current.0, start.0),
+            max(current.1, start.1),
+        )
+    }
+}
+
+// the innermost box the cursor is on or inside
+fn box_under_cursor(global_state: &GlobalState) -> Option<(i32, i32, i32, i32)> {
+    let (x, y) = global_state.current_pos;
+    global_state.diagram.boxes()
+        .into_iter()
+        .filter(|&(left, top, right, bottom)| {
+            (left..=right).contains(&x) && (top..=bottom).contains(&y)
+        })
+        .min_by_key(|&(left, top, right, bottom)| {
+            (right - left) as u32 * (bottom - top) as u32
+        })
+}
+
+// the innermost box whose border the cursor is on
+fn box_on_border(global_state: &GlobalState) -> Option<(i32, i32, i32, i32)> {
+    global_state.diagram.boxes()
+        .into_iter()
+        .filter(|&area| border_sides(area, global_state.current_pos) != 0)
+        .min_by_key(|&(left, top, right, bottom)| {
+            (right - left) as u32 * (bottom - top) as u32
+        })
+}
+
+// the sides a box's border joins at `pos`, 0 off the border
+fn border_sides((left, top, right, bottom): (i32, i32, i32, i32), (x, y): (i32, i32)) -> u8 {
+    let mut sides = 0;
+    if (y == top || y == bottom) && (left..=right).contains(&x) {
+        sides |= if x > left { LEFT } else { 0 } | if x < right { RIGHT } else { 0 };
+    }
+    if (x == left || x == right) && (top..=bottom).contains(&y) {
+        sides |= if y > top { UP } else { 0 } | if y < bottom { DOWN } else { 0 };
+    }
+    sides
+}
+
+// removes the box whose border the cursor is on, or else the arrow or line under it, leaving
+// glyphs shared with other boxes and lines joined to only what remains
+fn delete_shape(global_state: &mut GlobalState) -> Option<&'static str> {
+    let boxes = global_state.diagram.boxes();
+    let mut trims = Vec::new();
+    let mut removed = Vec::new();
+    let shape = if let Some(area) = box_on_border(global_state) {
+        let (left, top, right, bottom) = area;
+        let edge = (left..=right)
+            .flat_map(|x| [(x, top), (x, bottom)])
+            .chain((top + 1..bottom).flat_map(|y| [(left, y), (right, y)]));
+        for pos in edge {
+            let shared = boxes
+                .iter()
+                .filter(|other| **other != area)
+                .fold(0, |sides, other| sides | border_sides(*other, pos));
+            trims.push((pos, border_sides(area, pos) & !shared));
+        }
+        "box"
+    } else {
+        // a head joins the line behind it
+        let sides = |pos: (i32, i32)| {
+            let char = global_state.diagram.get(&pos)?.char;
+            head_entry(char).or_else(|| glyph_sides(char))
+        };
+        let on_box = |pos: (i32, i32)| boxes.iter().any(|area| border_sides(*area, pos) != 0);
+        let start = global_state.current_pos;
+        sides(start)?;
+        let mut stack = vec![start];
+        removed.push(start);
+        // junctions and box borders stay, only losing the side the line came in through
+        while let Some(pos) = stack.pop() {
+            for side in [UP, DOWN, LEFT, RIGHT] {
+                let next = step(pos, side);
+                if sides(pos).unwrap_or(0) & side == 0 || removed.contains(&next) {
+                    continue;
+                }
+                let Some(next_sides) = sides(next).filter(|sides| sides & opposite(side) != 0)
+                else {
+                    continue;
+                };
+                if on_box(next) || next_sides.count_ones() > 2 {
+                    trims.push((next, opposite(side)));
+                } else {
+                    removed.push(next);
+                    stack.push(next);
+                }
+            }
+        }
+        "line"
+    };
+    for pos in removed {
+        global_state.diagram.remove(&pos);
+    }
+    for (pos, cut) in trims {
+        let Some(cell) = global_state.diagram.get_mut(&pos) else {
+            continue;
+        };
+        // title text in a deleted border goes with it
+        let left = glyph_sides(cell.char).map_or(0, |sides| sides & !cut);
+        if left == 0 {
+            global_state.diagram.remove(&pos);
+        } else {
+            cell.char = glyph_like(cell.char, left);
+        }
+    }
+    global_state.dirty = true;
+    Some(shape)
+}
+
+// whitespace separated pairs, the first character of each becomes the second
+fn parse_char_map(input: &str) -> Result<HashMap<char, char>> {
+    let mut map = HashMap::new();
+    for pair in input.split_whitespace() {
+        let mut chars = pair.chars();
+        let (Some(from), Some(to), None) = (chars.next(), chars.next(), chars.next()) else {
+            anyhow::bail!("expected pairs of characters like *●, got {pair}");
+        };
+        map.insert(from, to);
+    }
+    Ok(map)
+}
+
+// swaps the characters of the selected cells, each keeping its color
+fn map_chars(global_state: &mut GlobalState, map: &HashMap<char, char>) {
+    let (left, top, right, bottom) = global_state.selection();
+    let mut changed = 0;
+    for ((x, y), cell) in global_state.diagram.iter_mut() {
+        let inside = (left..=right).contains(x) && (top..=bottom).contains(y);
+        if let Some(&to) = map.get(&cell.char).filter(|_| inside) {
+            cell.char = to;
+            changed += 1;
+        }
+    }
+    global_state.dirty |= changed > 0;
+    global_state.message = format!("mapped {changed} glyphs");
+}
+
+// `column,row text` as the coordinates label shows them, counted from 1, to a canvas cell and
+// the text, which keeps its spaces
+fn parse_place(input: &str, (width, height): (i32, i32)) -> Result<((i32, i32), &str)> {
+    let input = input.trim_start();
+    let (coords, text) = input.split_once(' ').unwrap_or((input, ""));
+    let pos = coords.split_once(',').and_then(|(x, y)| {
+        let (x, y) = (x.trim().parse::<i32>().ok()?, y.trim().parse::<i32>().ok()?);
+        Some((x.checked_sub(1)?, y.checked_sub(1)?))
+    });
+    match pos {
+        Some((x, y)) if !text.is_empty() && (0..width).contains(&x) && (0..height).contains(&y) => {
+            Ok(((x, y), text))
+        }
+        Some(_) if !text.is_empty() => anyhow::bail!("{coords} is off the {width}x{height} canvas"),
+        _ => anyhow::bail!("expected a column,row and text like 3,2 label, got {input}"),
+    }
+}
+
+// writes text rightwards from pos in the active color, cut at the right edge, returns the
+// column just past it
+fn place_text(global_state: &mut GlobalState, (mut x, y): (i32, i32), text: &str) -> i32 {
+    for char in text.chars() {
+        if x >= global_state.canvas_size().0 {
+            break;
+        }
+        let cell = global_state.colored(char);
+        global_state.diagram.insert((x, y), cell);
+        x += global_state.advance(char);
+    }
+    x
+}
+
+// `ROWSxCOLS`, optionally followed by the inside size of each cell as `WIDTHxHEIGHT`
+fn parse_table(input: &str) -> Result<(i32, i32, i32, i32)> {
+    let size = |text: &str| -> Option<(i32, i32)> {
+        let (a, b) = text.split_once('x')?;
+        Some((a.trim().parse().ok()?, b.trim().parse().ok()?))
+    };
+    let mut parts = input.split_whitespace();
+    let grid = parts.next().and_then(size);
+    let cell = parts.next().map_or(Some((8, 1)), size);
+    match (grid, cell, parts.next()) {
+        (Some((rows, cols)), Some((width, height)), None)
+            if rows > 0 && cols > 0 && width > 0 && height > 0 =>
+        {
+            Ok((rows, cols, width, height))
+        }
+        _ => anyhow::bail!("expected a table like 2x3 or 2x3 8x1, got {input}"),
+    }
+}
+
+// a grid of boxes sharing their borders, top-left corner at the cursor, with junctions where
+// the lines cross
+fn draw_table(
+    global_state: &mut GlobalState,
+    (rows, cols, width, height): (i32, i32, i32, i32),
+) -> Result<()> {
+    let glyphs = table_glyphs(
+        global_state.current_pos,
+        (rows, cols, width, height),
+        &global_state.config.glyphs,
+        global_state.config.max_canvas(),
+    )?;
+    let color = global_state.active_color;
+    global_state.diagram.draw(&glyphs, color);
+    global_state.dirty = true;
+    global_state.message = format!("drew a {rows}x{cols} table");
+    Ok(())
+}
+
+// writes the title into the top border as `┌ title ─┐`, a title that doesn't fit widens the
+// box and pushes whatever is right of it on the same rows, unless truncate_titles is set
+fn set_title(global_state: &mut GlobalState, title: &str) {
+    let Some((left, top, mut right, bottom)) = box_under_cursor(global_state) else {
+        global_state.message = "cursor is not on a box".to_string();
+        return;
+    };
+    let corner = global_state.diagram[&(left, top)];
+    let line = Cell { char: glyph_like(corner.char, LEFT | RIGHT), fg: corner.fg };
+    let mut title: Vec<char> = title.chars().collect();
+    let width = |title: &[char]| -> i32 {
+        title.iter().map(|char| global_state.advance(*char)).sum::<i32>() + 2
+    };
+    let interior = right - left - 1;
+    if width(&title) > interior {
+        if global_state.config.truncate_titles {
+            while !title.is_empty() && width(&title) > interior {
+                title.pop();
+            }
+        } else {
+            let grow = width(&title) - interior;
+            let moved: Vec<((i32, i32), Cell)> = global_state
+                .diagram
+                .iter()
+                .filter(|((x, y), _)| *x >= right && (top..=bottom).contains(y))
+                .map(|(pos, cell)| (*pos, *cell))
+                .collect();
+            for (pos, _) in &moved {
+                global_state.diagram.remove(pos);
+            }
+            for ((x, y), cell) in moved {
+                global_state.diagram.insert((x + grow, y), cell);
+            }
+            for x in right..right + grow {
+                global_state.diagram.insert((x, bottom), line);
+            }
+            right += grow;
+        }
+    }
+
+    // line cells either side of the title and its two spaces
+    let used = title.iter().map(|char| global_state.advance(*char)).sum::<i32>() + 2;
+    let spare = right - left - 1 - used;
+    for x in left + 1..right {
+        global_state.diagram.insert((x, top), line);
+    }
+    if !title.is_empty() {
+        let mut x = left
+            + 1
+            + match global_state.config.title_align {
+                TitleAlign::Left => 0,
+                TitleAlign::Center => spare / 2,
+                TitleAlign::CenterRight => (spare + 1) / 2,
+            };
+        global_state.diagram.insert((x, top), Cell { char: ' ', fg: None });
+        x += 1;
+        for char in title {
+            let cell = global_state.colored(char);
+            global_state.diagram.insert((x, top), cell);
+            x += 1;
+            // the cell under the right half of a wide glyph stays empty
+            for _ in 1..global_state.advance(char) {
+                global_state.diagram.remove(&(x, top));
+                x += 1;
+            }
+        }
+        global_state.diagram.insert((x, top), Cell { char: ' ', fg: None });
+    }
+    global_state.dirty = true;
+}
+
+// moves the cursor to the top-left corner of the next box in reading order, wrapping around
+fn jump_to_box(global_state: &mut GlobalState, forward: bool) {
+    let corners: Vec<(i32, i32)> = global_state.diagram.boxes()
+        .into_iter()
+        .map(|(left, top, _, _)| (left, top))
+        .collect();
+    let (x, y) = global_state.current_pos;
+    let target = if forward {
+        corners
+            .iter()
+            .find(|corner| (corner.1, corner.0) > (y, x))
+            .or(corners.first())
+    } else {
+        corners
+            .iter()
+            .rev()
+            .find(|corner| (corner.1, corner.0) < (y, x))
+            .or(corners.last())
+    };
+    match target {
+        Some(&corner) => {
+            global_state.prev_pos = global_state.current_pos;
+            global_state.current_pos = corner;
+        }
+        None => global_state.message = "no boxes".to_string(),
+    }
+}
+
+fn update_preview(global_state: &mut GlobalState) {
+    if global_state.input_state == InputState::BOX && !global_state.anchored {
+        global_state.preview.clear();
+    } else if global_state.input_state == InputState::BOX {
+        let sides = global_state.box_sides;
+        global_state.preview =
+            box_sides_glyphs(box_bounds(global_state), sides, &global_state.glyphs);
+    } else if global_state.input_state == InputState::ARROW {
+        build_arrow_preview(global_state);
+    } else if global_state.input_state == InputState::ELLIPSE {
+        build_ellipse_preview(global_state);
+    } else {
+        global_state.preview.clear();
+    }
 }